@@ -0,0 +1,29 @@
+use std::{sync::Arc, time::Duration};
+
+use midi_fundsp::{
+    io::{MsgQueue, SynthBuilder, console_choice_from, get_first_midi_device, start_input_thread},
+    sound_builders::SharedProgramTable,
+    sounds::options,
+    tunings::{FIVE_LIMIT, SEVEN_LIMIT, adaptive::AdaptiveJust},
+};
+use midir::MidiInput;
+use read_input::{InputBuild, shortcut::input};
+
+fn main() -> anyhow::Result<()> {
+    let ratios = vec![("5-Limit", FIVE_LIMIT), ("7-Limit", SEVEN_LIMIT)];
+    let ratios = ratios[console_choice_from("Ratios", &ratios, |r| r.0)].1;
+    let glides = vec![("Jump", 0), ("Quick glide", 50), ("Slow glide", 300)];
+    let glide = glides[console_choice_from("Retuning", &glides, |g| g.0)].1;
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    let in_port = get_first_midi_device(&mut midi_in)?;
+    let midi_msgs = Arc::new(MsgQueue::new());
+    start_input_thread(midi_msgs.clone(), midi_in, in_port);
+    SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_messages(midi_msgs)
+        .with_adaptive_tuning(AdaptiveJust::new(ratios).with_glide(Duration::from_millis(glide)))
+        .start()?;
+    input::<String>()
+        .msg("Play chords at will. Press any key to exit\n")
+        .get();
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use midi_fundsp::{
+    arpeggiator::{ArpMode, Arpeggiator},
+    io::{MsgQueue, SynthBuilder, console_choice_from, get_first_midi_device, start_input_thread},
+    sound_builders::SharedProgramTable,
+    sounds::options,
+};
+use midir::MidiInput;
+use read_input::{InputBuild, shortcut::input};
+
+fn main() -> anyhow::Result<()> {
+    let modes = vec![
+        ("Up", ArpMode::Up),
+        ("Down", ArpMode::Down),
+        ("Up/Down", ArpMode::UpDown),
+        ("Random", ArpMode::Random),
+        ("As played", ArpMode::AsPlayed),
+    ];
+    let mode = modes[console_choice_from("Mode", &modes, |m| m.0)].1;
+    let clocks = vec![("Internal tempo", false), ("MIDI clock", true)];
+    let midi_clock = clocks[console_choice_from("Clock", &clocks, |c| c.0)].1;
+    let mut arpeggiator = Arpeggiator::new(mode).with_octaves(2).with_latch(true);
+    if midi_clock {
+        arpeggiator = arpeggiator.with_midi_clock();
+    } else {
+        let bpm = input::<f32>().msg("Tempo (beats per minute): ").get();
+        arpeggiator = arpeggiator.with_tempo(bpm);
+    }
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    let in_port = get_first_midi_device(&mut midi_in)?;
+    let midi_msgs = Arc::new(MsgQueue::new());
+    start_input_thread(midi_msgs.clone(), midi_in, in_port);
+    SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_messages(midi_msgs)
+        .with_arpeggiator(arpeggiator)
+        .start()?;
+    input::<String>()
+        .msg("Play chords at will; each one plays until the next. Press any key to exit\n")
+        .get();
+    Ok(())
+}
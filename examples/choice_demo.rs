@@ -1,52 +1,51 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use crossbeam_queue::SegQueue;
-use crossbeam_utils::atomic::AtomicCell;
 use midi_fundsp::{
     io::{
-        Speaker, SynthMsg, choose_midi_device, console_choice_from, start_input_thread,
-        start_output_thread,
+        MsgQueue, Speaker, SynthBuilder, SynthMsg, choose_midi_device, console_choice_from,
+        start_input_thread,
     },
-    sound_builders::ProgramTable,
+    sound_builders::SharedProgramTable,
     sounds::options,
 };
 use midir::MidiInput;
 
 fn main() -> anyhow::Result<()> {
-    let reset = Arc::new(AtomicCell::new(false));
-    let mut quit = false;
-    while !quit {
+    let program_table = Arc::new(SharedProgramTable::new(options()));
+    loop {
         let mut midi_in = MidiInput::new("midir reading input")?;
         let in_port = choose_midi_device(&mut midi_in)?;
-        let midi_msgs = Arc::new(SegQueue::new());
-        while reset.load() {}
-        start_input_thread(midi_msgs.clone(), midi_in, in_port, reset.clone());
-        let program_table = Arc::new(Mutex::new(options()));
-        start_output_thread::<10>(midi_msgs.clone(), program_table.clone());
-        run_chooser(midi_msgs, program_table.clone(), reset.clone(), &mut quit);
+        let midi_msgs = Arc::new(MsgQueue::new());
+        let input = start_input_thread(midi_msgs.clone(), midi_in, in_port);
+        let synth = SynthBuilder::<10>::new(program_table.clone())
+            .with_messages(midi_msgs)
+            .start()?;
+        let quit = run_chooser(&synth.messages(), &program_table);
+        input.stop();
+        synth.stop();
+        input.join();
+        synth.join();
+        if quit {
+            return Ok(());
+        }
     }
-    Ok(())
 }
 
-fn run_chooser(
-    midi_msgs: Arc<SegQueue<SynthMsg>>,
-    program_table: Arc<Mutex<ProgramTable>>,
-    reset: Arc<AtomicCell<bool>>,
-    quit: &mut bool,
-) {
+/// Returns `true` if the user chose to quit, or `false` to pick a new MIDI device.
+fn run_chooser(midi_msgs: &MsgQueue<SynthMsg>, program_table: &SharedProgramTable) -> bool {
     let main_menu = vec!["Pick New Synthesizer Sound", "Pick New MIDI Device", "Quit"];
-    while !*quit && !reset.load() {
+    loop {
         println!("Play notes at will. When ready for a change, select one of the following:");
         match console_choice_from("Choice", &main_menu, |s| *s) {
             0 => {
-                let program = {
-                    let program_table = program_table.lock().unwrap();
-                    console_choice_from("Change synth to", &program_table, |opt| opt.0.as_str())
-                };
+                let program =
+                    console_choice_from("Change synth to", &program_table.snapshot(), |opt| {
+                        opt.0.as_str()
+                    });
                 midi_msgs.push(SynthMsg::program_change(program as u8, Speaker::Both));
             }
-            1 => reset.store(true),
-            2 => *quit = true,
+            1 => return false,
+            2 => return true,
             _ => panic!("This should never happen."),
         }
     }
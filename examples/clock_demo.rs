@@ -0,0 +1,74 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::bail;
+use midi_fundsp::{
+    arpeggiator::{ArpMode, Arpeggiator},
+    clock::start_clock_thread,
+    io::{MsgQueue, SynthBuilder, console_choice_from, get_first_midi_device, start_input_thread},
+    sound_builders::SharedProgramTable,
+    sounds::options,
+};
+use midir::{MidiInput, MidiOutput};
+use read_input::{InputBuild, shortcut::input};
+
+fn main() -> anyhow::Result<()> {
+    let roles = vec!["Follow an external MIDI clock", "Send MIDI clock as master"];
+    if console_choice_from("Role", &roles, |r| r) == 0 {
+        follow()
+    } else {
+        lead()
+    }
+}
+
+/// Arpeggiates chords in time with the clock received from the first MIDI device, reporting its tempo.
+fn follow() -> anyhow::Result<()> {
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    let in_port = get_first_midi_device(&mut midi_in)?;
+    let midi_msgs = Arc::new(MsgQueue::new());
+    start_input_thread(midi_msgs.clone(), midi_in, in_port);
+    let synth = SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_messages(midi_msgs)
+        .with_arpeggiator(Arpeggiator::new(ArpMode::Up).with_midi_clock())
+        .start()?;
+    let clock = synth.clock();
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(Duration::from_secs(2));
+            match clock.tempo() {
+                Some(bpm) if clock.is_running() => {
+                    println!("{bpm:.1} bpm, beat {:.0}", clock.beats())
+                }
+                Some(bpm) => println!("{bpm:.1} bpm, stopped"),
+                None => println!("Waiting for MIDI clock"),
+            }
+        }
+    });
+    input::<String>()
+        .msg("Play chords at will. Press any key to exit\n")
+        .get();
+    Ok(())
+}
+
+/// Sends MIDI clock to the first MIDI output port.
+fn lead() -> anyhow::Result<()> {
+    let midi_out = MidiOutput::new("midir clock output")?;
+    let Some(out_port) = midi_out.ports().first().cloned() else {
+        bail!("No MIDI output ports available");
+    };
+    println!("Sending clock to {}", midi_out.port_name(&out_port)?);
+    let mut connection = midi_out
+        .connect(&out_port, "midi_fundsp clock")
+        .map_err(|err| anyhow::anyhow!("{err}"))?;
+    let bpm = input::<f32>().msg("Tempo (beats per minute): ").get();
+    let master = start_clock_thread(bpm, move |msg, _| {
+        if let Err(err) = connection.send(&msg.to_midi()) {
+            eprintln!("Error sending clock: {err}");
+        }
+    });
+    master.send_start();
+    input::<String>().msg("Press any key to stop\n").get();
+    master.send_stop();
+    master.stop();
+    master.join();
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use std::{sync::Arc, time::Duration};
+
+use midi_fundsp::{
+    io::{MsgQueue, Speaker, SynthBuilder, get_first_midi_device, start_input_thread},
+    looper::Looper,
+    sound_builders::SharedProgramTable,
+    sounds::options,
+    zones::Zone,
+};
+use midi_msg::Channel;
+use midir::MidiInput;
+use read_input::{InputBuild, shortcut::input};
+
+fn main() -> anyhow::Result<()> {
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    let in_port = get_first_midi_device(&mut midi_in)?;
+    let live_msgs = Arc::new(MsgQueue::new());
+    start_input_thread(live_msgs.clone(), midi_in, in_port);
+
+    // Live playing on channel 1 sounds on the left, and the loop, played back on channel 16, on the right.
+    let synth = SynthBuilder::<20>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_zones(vec![
+            Zone::new(0)
+                .with_channel(Channel::Ch1)
+                .with_speaker(Speaker::Left),
+            Zone::new(3)
+                .with_channel(Channel::Ch16)
+                .with_speaker(Speaker::Right),
+        ])
+        .start()?;
+    let bpm = input::<f64>().msg("Tempo (beats per minute): ").get();
+    let bar = Duration::from_secs_f64(4.0 * 60.0 / bpm);
+    let looper = Looper::new()
+        .with_quantum(bar)
+        .with_channel(Channel::Ch16)
+        .start(live_msgs, synth.messages());
+
+    loop {
+        let command = input::<String>()
+            .msg("r: record/overdub on or off, u: undo, c: clear, q: quit\n")
+            .get();
+        match command.trim() {
+            "r" => looper.record(),
+            "u" => looper.undo(),
+            "c" => looper.clear(),
+            "q" => break,
+            _ => {}
+        }
+    }
+    looper.stop();
+    looper.join();
+    Ok(())
+}
@@ -1,9 +1,8 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use crossbeam_queue::SegQueue;
-use crossbeam_utils::atomic::AtomicCell;
 use midi_fundsp::{
-    io::{get_first_midi_device, start_midi_input_thread, start_midi_output_thread},
+    io::{MsgQueue, SynthBuilder, get_first_midi_device, start_midi_input_thread},
+    sound_builders::SharedProgramTable,
     sounds::options,
 };
 use midir::MidiInput;
@@ -12,10 +11,11 @@ use read_input::{InputBuild, shortcut::input};
 fn main() -> anyhow::Result<()> {
     let mut midi_in = MidiInput::new("midir reading input")?;
     let in_port = get_first_midi_device(&mut midi_in)?;
-    let midi_msgs = Arc::new(SegQueue::new());
-    let quit = Arc::new(AtomicCell::new(false));
-    start_midi_input_thread(midi_msgs.clone(), midi_in, in_port, quit.clone());
-    start_midi_output_thread::<10>(midi_msgs, Arc::new(Mutex::new(options())));
+    let midi_msgs = Arc::new(MsgQueue::new());
+    start_midi_input_thread(midi_msgs.clone(), midi_in, in_port);
+    SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_midi_messages(midi_msgs)
+        .start()?;
     input::<String>().msg("Press any key to exit\n").get();
     Ok(())
 }
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use midi_fundsp::{
+    io::{
+        MsgQueue, OutputConfig, SynthBuilder, choose_midi_device, choose_output_device,
+        start_input_thread,
+    },
+    sound_builders::SharedProgramTable,
+    sounds::options,
+};
+use midir::MidiInput;
+use read_input::{InputBuild, shortcut::input};
+
+fn main() -> anyhow::Result<()> {
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    let in_port = choose_midi_device(&mut midi_in)?;
+    let output = OutputConfig::default()
+        .with_device(choose_output_device()?)
+        .with_buffer_size(input().msg("Frames per buffer: ").get());
+    let midi_msgs = Arc::new(MsgQueue::new());
+    start_input_thread(midi_msgs.clone(), midi_in, in_port);
+    SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_messages(midi_msgs)
+        .with_output(output)
+        .start()?;
+    input::<String>().msg("Press any key to exit\n").get();
+    Ok(())
+}
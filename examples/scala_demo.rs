@@ -0,0 +1,32 @@
+use std::sync::Arc;
+
+use midi_fundsp::{
+    io::{MsgQueue, SynthBuilder, get_first_midi_device, start_input_thread},
+    sound_builders::SharedProgramTable,
+    sounds::options,
+    tunings::scala::ScalaTuning,
+};
+use midir::MidiInput;
+use read_input::{InputBuild, shortcut::input};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let tuning = match args.len() {
+        2 => ScalaTuning::load(&args[1])?,
+        3 => ScalaTuning::load_mapped(&args[1], &args[2])?,
+        _ => {
+            println!("Usage: scala_demo scale.scl [mapping.kbm]");
+            return Ok(());
+        }
+    };
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    let in_port = get_first_midi_device(&mut midi_in)?;
+    let midi_msgs = Arc::new(MsgQueue::new());
+    start_input_thread(midi_msgs.clone(), midi_in, in_port);
+    SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_messages(midi_msgs)
+        .with_tuning(Arc::new(tuning))
+        .start()?;
+    input::<String>().msg("Press any key to exit\n").get();
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use midi_fundsp::{
+    io::{Speaker, SynthBuilder},
+    sequencer::{Pattern, Sequencer, Step, Track},
+    sound_builders::SharedProgramTable,
+    sounds::options,
+};
+use read_input::{InputBuild, shortcut::input};
+
+fn bass(root: u8) -> Pattern {
+    Pattern::new(vec![
+        Step::note(root).tied(),
+        Step::note(root),
+        Step::rest(),
+        Step::note(root + 12).with_velocity(70),
+        Step::note(root).with_gate(0.25),
+        Step::rest(),
+        Step::note(root + 7),
+        Step::note(root + 10).with_velocity(80),
+    ])
+    .with_rate(2)
+}
+
+fn main() -> anyhow::Result<()> {
+    let synth = SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options()))).start()?;
+    let melody = Pattern::new(
+        [67, 70, 72, 74, 72, 70, 67, 65]
+            .iter()
+            .map(|note| Step::note(*note).with_gate(0.8).with_velocity(90))
+            .collect(),
+    )
+    .with_rate(1);
+    let sequencer = Sequencer::new(
+        110.0,
+        vec![
+            Track::new(bass(36)).with_speaker(Speaker::Left),
+            Track::new(melody).with_speaker(Speaker::Right),
+        ],
+    )
+    .start(synth.messages());
+    input::<String>()
+        .msg("Press any key to change the bass line\n")
+        .get();
+    sequencer.set_pattern(0, bass(41));
+    input::<String>().msg("Press any key to exit\n").get();
+    sequencer.stop();
+    sequencer.join();
+    synth.stop();
+    synth.join();
+    Ok(())
+}
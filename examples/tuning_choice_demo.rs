@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use midi_fundsp::{
+    io::{MsgQueue, SynthBuilder, console_choice_from, get_first_midi_device, start_input_thread},
+    sound_builders::SharedProgramTable,
+    sounds::options,
+    tunings::{
+        EqualTemperament, FIVE_LIMIT, RatioTuning, SEVEN_LIMIT, Temperament, Tuning,
+        well_temperament,
+    },
+};
+use midir::MidiInput;
+
+const KEYS: [&str; 12] = [
+    "C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+
+fn main() -> anyhow::Result<()> {
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    let in_port = get_first_midi_device(&mut midi_in)?;
+    let midi_msgs = Arc::new(MsgQueue::new());
+    let input = start_input_thread(midi_msgs.clone(), midi_in, in_port);
+    let synth = SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_messages(midi_msgs)
+        .start()?;
+    let main_menu = vec![
+        "Equal Temperament",
+        "Well Temperament",
+        "5-Limit Just Intonation",
+        "7-Limit Just Intonation",
+        "31-EDO",
+        "Bohlen-Pierce",
+        "Werckmeister III (A = 415)",
+        "Quarter-Comma Meantone",
+        "Quit",
+    ];
+    loop {
+        println!("Play notes at will. When ready for a change, select one of the following:");
+        let tuning: Box<dyn Tuning> = match console_choice_from("Tuning", &main_menu, |s| *s) {
+            0 => Box::new(fundsp::math::midi_hz::<f32>),
+            1 => Box::new(well_temperament),
+            2 => Box::new(RatioTuning::new(FIVE_LIMIT, choose_tonic(), 440.0)),
+            3 => Box::new(RatioTuning::new(SEVEN_LIMIT, choose_tonic(), 440.0)),
+            4 => Box::new(EqualTemperament::edo(31, 69, 440.0)),
+            5 => Box::new(EqualTemperament::bohlen_pierce(60, 261.626)),
+            6 => Box::new(Temperament::werckmeister_iii(0, 415.0)),
+            7 => Box::new(Temperament::quarter_comma_meantone(
+                choose_tonic() % 12,
+                440.0,
+            )),
+            _ => break,
+        };
+        synth.set_tuning(tuning.as_ref());
+    }
+    input.stop();
+    synth.stop();
+    input.join();
+    synth.join();
+    Ok(())
+}
+
+fn choose_tonic() -> u8 {
+    60 + console_choice_from("Tonic", &KEYS.to_vec(), |s| *s) as u8
+}
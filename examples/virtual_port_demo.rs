@@ -0,0 +1,27 @@
+#[cfg(unix)]
+fn main() -> anyhow::Result<()> {
+    use std::sync::Arc;
+
+    use midi_fundsp::{
+        io::{MsgQueue, SynthBuilder, start_virtual_input_thread},
+        sound_builders::SharedProgramTable,
+        sounds::options,
+    };
+    use midir::MidiInput;
+    use read_input::{InputBuild, shortcut::input};
+
+    let midi_in = MidiInput::new("midir reading input")?;
+    let midi_msgs = Arc::new(MsgQueue::new());
+    start_virtual_input_thread(midi_msgs.clone(), midi_in, "midi_fundsp synth");
+    SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_messages(midi_msgs)
+        .start()?;
+    println!("Connect a DAW or sequencer to the MIDI port \"midi_fundsp synth\".");
+    input::<String>().msg("Press any key to exit\n").get();
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn main() {
+    println!("Virtual MIDI ports are not available on this platform.");
+}
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use midi_fundsp::{
+    io::{MsgQueue, Speaker, SynthBuilder, SynthMsg, get_first_midi_device, start_input_thread},
+    sinks::WavFileSink,
+    sound_builders::SharedProgramTable,
+    sounds::options,
+};
+use midir::MidiInput;
+use read_input::{InputBuild, shortcut::input};
+
+fn main() -> anyhow::Result<()> {
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    let in_port = get_first_midi_device(&mut midi_in)?;
+    let midi_msgs = Arc::new(MsgQueue::new());
+    start_input_thread(midi_msgs.clone(), midi_in, in_port);
+    let sink = WavFileSink::create("recording.wav", 44100)?;
+    let synth = SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+        .with_messages(midi_msgs.clone())
+        .with_sink(sink)
+        .start()?;
+    println!("Recording to recording.wav without playing any sound.");
+    input::<String>().msg("Press any key to exit\n").get();
+    midi_msgs.push(SynthMsg::system_reset(Speaker::Both));
+    // The recording is finalized once the synthesizer's threads have exited.
+    synth.join();
+    Ok(())
+}
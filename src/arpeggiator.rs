@@ -0,0 +1,558 @@
+//! An arpeggiator, which plays the notes of held chords one at a time in a repeating pattern.
+//!
+//! The arpeggiator sits between a synthesizer's incoming messages and its players. It consumes `Note On` and
+//! `Note Off` messages, and in their place sends the notes of its pattern, one per step. Steps follow either
+//! the arpeggiator's own tempo or the MIDI `Timing Clock` messages sent by a sequencer or drum machine,
+//! 24 of which make up each beat. Every other message, clock messages included, passes through unchanged.
+
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    time::{Duration, Instant},
+};
+
+use fundsp::math::rnd1;
+use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg, SystemCommonMsg, SystemRealTimeMsg};
+
+use crate::{
+    MAX_MIDI_VALUE,
+    clock::{CLOCKS_PER_BEAT, CLOCKS_PER_MIDI_BEAT},
+    io::{Speaker, SynthMsg},
+};
+
+/// Time allowed for the notes of a chord to arrive before an arpeggiator following its own tempo takes
+/// its first step, so that the pattern starts from the whole chord rather than from whichever note came first.
+pub const CHORD_WINDOW: Duration = Duration::from_millis(20);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// The order in which an arpeggiator plays the held notes.
+pub enum ArpMode {
+    /// Lowest to highest, then repeat.
+    Up,
+    /// Highest to lowest, then repeat.
+    Down,
+    /// Lowest to highest and back, without repeating the highest or lowest note.
+    UpDown,
+    /// A randomly chosen note at each step.
+    Random,
+    /// The order in which the notes were played.
+    AsPlayed,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+/// What determines when an arpeggiator takes its steps.
+pub enum ArpClock {
+    /// The arpeggiator's own tempo, starting `CHORD_WINDOW` after the first `Note On`.
+    Internal,
+    /// Incoming MIDI `Timing Clock` messages. MIDI `Start` restarts the pattern, and MIDI `Stop` silences it
+    /// until the next `Start` or `Continue`, however many `Timing Clock` messages arrive meanwhile.
+    /// A `Song Position Pointer` moves the pattern to the step at its position.
+    Midi,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// Settings for an arpeggiator.
+pub struct Arpeggiator {
+    /// Order of the notes.
+    pub mode: ArpMode,
+    /// Number of octaves over which the held notes are repeated, starting from where they were played.
+    pub octaves: u8,
+    /// Number of steps in each beat: `1` plays quarter notes, `2` eighth notes, and `4` sixteenth notes.
+    pub steps_per_beat: u8,
+    /// Fraction of each step, from `0.0` to `1.0`, for which its note sounds.
+    pub gate: f32,
+    /// Tempo in beats per minute when following the `ArpClock::Internal` clock.
+    pub bpm: f32,
+    /// What determines when steps are taken.
+    pub clock: ArpClock,
+    /// If `true`, the pattern continues after all keys are released, until a new chord is played.
+    pub latch: bool,
+    /// Seed of the `ArpMode::Random` sequence. If `None`, each arpeggiator draws a seed of its own.
+    pub seed: Option<u64>,
+}
+
+impl Arpeggiator {
+    /// Creates an arpeggiator in `mode` that plays sixteenth notes over one octave at 120 beats per minute,
+    /// each lasting half of its step.
+    pub fn new(mode: ArpMode) -> Self {
+        Self {
+            mode,
+            octaves: 1,
+            steps_per_beat: 4,
+            gate: 0.5,
+            bpm: 120.0,
+            clock: ArpClock::Internal,
+            latch: false,
+            seed: None,
+        }
+    }
+
+    /// Repeats the held notes over `octaves` octaves.
+    pub fn with_octaves(mut self, octaves: u8) -> Self {
+        self.octaves = octaves.max(1);
+        self
+    }
+
+    /// Takes `steps_per_beat` steps in each beat.
+    pub fn with_rate(mut self, steps_per_beat: u8) -> Self {
+        self.steps_per_beat = steps_per_beat.max(1);
+        self
+    }
+
+    /// Sounds each note for the fraction `gate` of its step.
+    pub fn with_gate(mut self, gate: f32) -> Self {
+        self.gate = gate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Follows an internal tempo of `bpm` beats per minute.
+    pub fn with_tempo(mut self, bpm: f32) -> Self {
+        self.bpm = bpm;
+        self.clock = ArpClock::Internal;
+        self
+    }
+
+    /// Follows incoming MIDI `Timing Clock` messages rather than an internal tempo.
+    pub fn with_midi_clock(mut self) -> Self {
+        self.clock = ArpClock::Midi;
+        self
+    }
+
+    /// Continues the pattern after all keys are released if `latch` is `true`.
+    pub fn with_latch(mut self, latch: bool) -> Self {
+        self.latch = latch;
+        self
+    }
+
+    /// Plays the same `ArpMode::Random` sequence every time, as determined by `seed`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Returns the duration of one step at the internal tempo.
+    fn step_duration(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / (self.bpm.max(1.0) as f64 * self.steps_per_beat as f64))
+    }
+
+    /// Returns the number of MIDI `Timing Clock` messages in one step.
+    fn clocks_per_step(&self) -> u32 {
+        (CLOCKS_PER_BEAT / self.steps_per_beat as u32).max(1)
+    }
+}
+
+/// Plays the pattern of an `Arpeggiator` in response to incoming messages and the passage of time.
+pub(crate) struct ArpEngine {
+    settings: Arpeggiator,
+    /// Notes and velocities in the order played.
+    held: Vec<(u8, u8)>,
+    keys_down: usize,
+    step: usize,
+    random_seed: u64,
+    sounding: Option<(u8, Instant)>,
+    next_step: Option<Instant>,
+    clocks: u32,
+    /// `false` between a MIDI `Stop` and the next `Start` or `Continue`.
+    running: bool,
+    last_clock: Option<Instant>,
+    clock_period: Option<Duration>,
+    channel: Channel,
+    speaker: Speaker,
+}
+
+impl ArpEngine {
+    pub(crate) fn new(settings: Arpeggiator) -> Self {
+        Self {
+            settings,
+            held: vec![],
+            keys_down: 0,
+            step: 0,
+            random_seed: settings
+                .seed
+                .unwrap_or_else(|| RandomState::new().build_hasher().finish()),
+            sounding: None,
+            next_step: None,
+            clocks: 0,
+            running: true,
+            last_clock: None,
+            clock_period: None,
+            channel: Channel::Ch1,
+            speaker: Speaker::Both,
+        }
+    }
+
+    /// Returns the next moment at which `tick()` has something to do, if any.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        let release = self.sounding.map(|(_, off)| off);
+        match (release, self.next_step) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Adds to `out` every message due at or before `now`.
+    pub(crate) fn tick(&mut self, now: Instant, out: &mut Vec<SynthMsg>) {
+        while let Some(deadline) = self.next_deadline()
+            && deadline <= now
+        {
+            if let Some((_, off)) = self.sounding
+                && off == deadline
+            {
+                self.release(off, out);
+            } else {
+                self.take_step(deadline, self.settings.step_duration(), out);
+                self.next_step = Some(deadline + self.settings.step_duration());
+            }
+        }
+    }
+
+    /// Handles `msg`, received at `time`, adding to `out` any messages to send in response.
+    /// Call `tick()` with `time` first, so that the messages in `out` remain in order.
+    pub(crate) fn handle(&mut self, msg: SynthMsg, time: Instant, out: &mut Vec<SynthMsg>) {
+        match &msg.msg {
+            MidiMsg::ChannelVoice {
+                channel,
+                msg: ChannelVoiceMsg::NoteOn { note, velocity },
+            } if *velocity > 0 => {
+                if self.keys_down == 0 && self.settings.latch {
+                    self.held.clear();
+                }
+                self.keys_down += 1;
+                self.channel = *channel;
+                self.speaker = msg.speaker;
+                self.held.retain(|(held, _)| held != note);
+                self.held.push((*note, *velocity));
+                if self.settings.clock == ArpClock::Internal && self.next_step.is_none() {
+                    self.step = 0;
+                    self.next_step = Some(time + CHORD_WINDOW);
+                }
+            }
+            MidiMsg::ChannelVoice {
+                msg: ChannelVoiceMsg::NoteOn { note, .. } | ChannelVoiceMsg::NoteOff { note, .. },
+                ..
+            } => {
+                self.keys_down = self.keys_down.saturating_sub(1);
+                if !self.settings.latch {
+                    self.held.retain(|(held, _)| held != note);
+                    if self.held.is_empty() {
+                        self.stop(time, out);
+                    }
+                }
+            }
+            MidiMsg::SystemRealTime { msg: real_time } if self.settings.clock == ArpClock::Midi => {
+                match real_time {
+                    SystemRealTimeMsg::TimingClock => self.clock(time, out),
+                    SystemRealTimeMsg::Start => {
+                        self.clocks = 0;
+                        self.step = 0;
+                        self.running = true;
+                    }
+                    SystemRealTimeMsg::Continue => self.running = true,
+                    SystemRealTimeMsg::Stop => {
+                        self.running = false;
+                        self.stop(time, out);
+                    }
+                    _ => {}
+                }
+                out.push(msg);
+            }
+            MidiMsg::SystemCommon {
+                msg: SystemCommonMsg::SongPosition(midi_beats),
+            } if self.settings.clock == ArpClock::Midi => {
+                // The next `Timing Clock` plays the new position, so the pattern resumes on the grid.
+                self.clocks = *midi_beats as u32 * CLOCKS_PER_MIDI_BEAT;
+                self.step = self.clocks.div_ceil(self.settings.clocks_per_step()) as usize;
+                out.push(msg);
+            }
+            _ => out.push(msg),
+        }
+    }
+
+    fn clock(&mut self, time: Instant, out: &mut Vec<SynthMsg>) {
+        if let Some(last) = self.last_clock {
+            self.clock_period = Some(time - last);
+        }
+        self.last_clock = Some(time);
+        if !self.running {
+            return;
+        }
+        let clocks_per_step = self.settings.clocks_per_step();
+        if self.clocks.is_multiple_of(clocks_per_step) {
+            let duration = self
+                .clock_period
+                .map_or(self.settings.step_duration(), |period| {
+                    period * clocks_per_step
+                });
+            self.take_step(time, duration, out);
+        }
+        self.clocks += 1;
+    }
+
+    fn take_step(&mut self, time: Instant, duration: Duration, out: &mut Vec<SynthMsg>) {
+        self.release(time, out);
+        let pattern = self.pattern();
+        if pattern.is_empty() {
+            return;
+        }
+        let index = if self.settings.mode == ArpMode::Random {
+            self.random_seed = self.random_seed.wrapping_add(1);
+            (rnd1(self.random_seed) * pattern.len() as f64) as usize % pattern.len()
+        } else {
+            self.step % pattern.len()
+        };
+        self.step += 1;
+        let (note, velocity) = pattern[index];
+        out.push(self.note_msg(ChannelVoiceMsg::NoteOn { note, velocity }, time));
+        self.sounding = Some((note, time + duration.mul_f32(self.settings.gate)));
+    }
+
+    fn release(&mut self, time: Instant, out: &mut Vec<SynthMsg>) {
+        if let Some((note, _)) = self.sounding.take() {
+            out.push(self.note_msg(ChannelVoiceMsg::NoteOff { note, velocity: 0 }, time));
+        }
+    }
+
+    fn stop(&mut self, time: Instant, out: &mut Vec<SynthMsg>) {
+        self.release(time, out);
+        self.next_step = None;
+    }
+
+    /// Returns the notes of one cycle of the pattern, with their velocities.
+    fn pattern(&self) -> Vec<(u8, u8)> {
+        let mut notes = self.held.clone();
+        if self.settings.mode != ArpMode::AsPlayed {
+            notes.sort_unstable();
+        }
+        let mut pattern = (0..self.settings.octaves)
+            .flat_map(|octave| {
+                notes.iter().filter_map(move |(note, velocity)| {
+                    let note = *note as u32 + 12 * octave as u32;
+                    (note <= MAX_MIDI_VALUE as u32).then_some((note as u8, *velocity))
+                })
+            })
+            .collect::<Vec<_>>();
+        match self.settings.mode {
+            ArpMode::Down => pattern.reverse(),
+            ArpMode::UpDown if pattern.len() > 2 => {
+                let descent = pattern[1..pattern.len() - 1]
+                    .iter()
+                    .rev()
+                    .copied()
+                    .collect::<Vec<_>>();
+                pattern.extend(descent);
+            }
+            _ => {}
+        }
+        pattern
+    }
+
+    fn note_msg(&self, msg: ChannelVoiceMsg, time: Instant) -> SynthMsg {
+        SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: self.channel,
+                msg,
+            },
+            speaker: self.speaker,
+            time: Some(time),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg, SystemCommonMsg, SystemRealTimeMsg};
+
+    use crate::{
+        arpeggiator::{ArpEngine, ArpMode, Arpeggiator, CHORD_WINDOW},
+        clock::CLOCKS_PER_BEAT,
+        io::{Speaker, SynthMsg},
+    };
+
+    fn note(msg: ChannelVoiceMsg) -> SynthMsg {
+        SynthMsg::new(
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg,
+            },
+            Speaker::Both,
+        )
+    }
+
+    fn notes_on(out: &[SynthMsg]) -> Vec<u8> {
+        out.iter()
+            .filter_map(|msg| match msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg: ChannelVoiceMsg::NoteOn { note, .. },
+                    ..
+                } => Some(note),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_arpeggiator_internal_tempo() {
+        // Sixteenth notes at 120 beats per minute take 125 ms each.
+        let mut arp = ArpEngine::new(Arpeggiator::new(ArpMode::UpDown).with_octaves(2));
+        let start = Instant::now();
+        let mut out = vec![];
+        for key in [64, 60] {
+            arp.handle(
+                note(ChannelVoiceMsg::NoteOn {
+                    note: key,
+                    velocity: 100,
+                }),
+                start,
+                &mut out,
+            );
+        }
+        assert!(out.is_empty());
+        assert_eq!(arp.next_deadline(), Some(start + CHORD_WINDOW));
+        arp.tick(start + CHORD_WINDOW + Duration::from_millis(875), &mut out);
+        assert_eq!(notes_on(&out), vec![60, 64, 72, 76, 72, 64, 60, 64]);
+        assert_eq!(out.len(), 15);
+        assert!(out.windows(2).all(|pair| pair[0].time <= pair[1].time));
+
+        out.clear();
+        arp.handle(
+            note(ChannelVoiceMsg::NoteOff {
+                note: 60,
+                velocity: 0,
+            }),
+            start + Duration::from_millis(900),
+            &mut out,
+        );
+        arp.handle(
+            note(ChannelVoiceMsg::NoteOff {
+                note: 64,
+                velocity: 0,
+            }),
+            start + Duration::from_millis(900),
+            &mut out,
+        );
+        assert_eq!(out.len(), 1);
+        assert_eq!(arp.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_arpeggiator_random_seed() {
+        let play = |settings: Arpeggiator| {
+            let mut arp = ArpEngine::new(settings);
+            let start = Instant::now();
+            let mut out = vec![];
+            for key in [60, 62, 64, 65] {
+                arp.handle(
+                    note(ChannelVoiceMsg::NoteOn {
+                        note: key,
+                        velocity: 100,
+                    }),
+                    start,
+                    &mut out,
+                );
+            }
+            arp.tick(start + Duration::from_secs(4), &mut out);
+            notes_on(&out)
+        };
+        let random = Arpeggiator::new(ArpMode::Random);
+        assert_eq!(play(random.with_seed(7)), play(random.with_seed(7)));
+        // Unseeded arpeggiators play different sequences of 32 steps, but for a vanishing chance.
+        assert_ne!(play(random), play(random));
+    }
+
+    #[test]
+    fn test_arpeggiator_midi_clock_latch() {
+        let mut arp = ArpEngine::new(
+            Arpeggiator::new(ArpMode::Down)
+                .with_midi_clock()
+                .with_rate(2)
+                .with_latch(true),
+        );
+        let start = Instant::now();
+        let mut out = vec![];
+        for key in [60, 64, 67] {
+            arp.handle(
+                note(ChannelVoiceMsg::NoteOn {
+                    note: key,
+                    velocity: 100,
+                }),
+                start,
+                &mut out,
+            );
+        }
+        // Released keys remain latched.
+        for key in [60, 64, 67] {
+            arp.handle(
+                note(ChannelVoiceMsg::NoteOff {
+                    note: key,
+                    velocity: 0,
+                }),
+                start,
+                &mut out,
+            );
+        }
+        assert!(out.is_empty());
+        let clock = SynthMsg::new(
+            MidiMsg::SystemRealTime {
+                msg: SystemRealTimeMsg::TimingClock,
+            },
+            Speaker::Both,
+        );
+        for i in 0..2 * CLOCKS_PER_BEAT {
+            let time = start + Duration::from_millis(20) * i;
+            arp.tick(time, &mut out);
+            arp.handle(clock.clone(), time, &mut out);
+        }
+        assert_eq!(notes_on(&out), vec![67, 64, 60, 67]);
+    }
+
+    #[test]
+    fn test_arpeggiator_midi_stop() {
+        let mut arp = ArpEngine::new(Arpeggiator::new(ArpMode::Up).with_midi_clock().with_rate(1));
+        let start = Instant::now();
+        let mut out = vec![];
+        for key in [60, 64] {
+            arp.handle(
+                note(ChannelVoiceMsg::NoteOn {
+                    note: key,
+                    velocity: 100,
+                }),
+                start,
+                &mut out,
+            );
+        }
+        let mut time = start;
+        let mut send = |arp: &mut ArpEngine, msg: MidiMsg, out: &mut Vec<SynthMsg>| {
+            time += Duration::from_millis(20);
+            arp.tick(time, out);
+            arp.handle(SynthMsg::new(msg, Speaker::Both), time, out);
+        };
+        let real_time = |msg| MidiMsg::SystemRealTime { msg };
+        let clock = real_time(SystemRealTimeMsg::TimingClock);
+        send(&mut arp, real_time(SystemRealTimeMsg::Start), &mut out);
+        for _ in 0..CLOCKS_PER_BEAT {
+            send(&mut arp, clock.clone(), &mut out);
+        }
+        assert_eq!(notes_on(&out), vec![60]);
+
+        // A master keeps sending clocks while stopped, which must not advance the pattern.
+        send(&mut arp, real_time(SystemRealTimeMsg::Stop), &mut out);
+        for _ in 0..2 * CLOCKS_PER_BEAT {
+            send(&mut arp, clock.clone(), &mut out);
+        }
+        assert_eq!(notes_on(&out), vec![60]);
+
+        // Relocating half a beat into the song leaves half a beat before the second step.
+        let relocate = MidiMsg::SystemCommon {
+            msg: SystemCommonMsg::SongPosition(2),
+        };
+        send(&mut arp, relocate, &mut out);
+        send(&mut arp, real_time(SystemRealTimeMsg::Continue), &mut out);
+        for _ in 0..CLOCKS_PER_BEAT / 2 {
+            send(&mut arp, clock.clone(), &mut out);
+        }
+        assert_eq!(notes_on(&out), vec![60]);
+        send(&mut arp, clock, &mut out);
+        assert_eq!(notes_on(&out), vec![60, 64]);
+    }
+}
@@ -0,0 +1,311 @@
+//! MIDI clock, through which a synthesizer follows the tempo and transport of a DAW or drum machine, or leads them.
+//!
+//! A clock master sends 24 `Timing Clock` messages in each beat, along with `Start`, `Stop`, and `Continue`
+//! messages for its transport and `Song Position Pointer` messages to locate a point in the song. Every
+//! synthesizer tracks these messages in a `MidiClock`, from which tempo-synced features may read the tempo
+//! and position of the master. `start_clock_thread()` makes a program the master instead.
+
+use std::{
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam_utils::atomic::AtomicCell;
+use midi_msg::{MidiMsg, SystemCommonMsg, SystemRealTimeMsg};
+
+use crate::io::MsgQueue;
+
+/// Number of MIDI `Timing Clock` messages in each beat.
+pub const CLOCKS_PER_BEAT: u32 = 24;
+
+/// Number of MIDI `Timing Clock` messages in each MIDI beat, the unit of a `Song Position Pointer`.
+pub const CLOCKS_PER_MIDI_BEAT: u32 = 6;
+
+/// Weight of each new interval between clocks in the estimated tempo. Smaller weights steady the tempo
+/// against jitter, but follow changes more slowly.
+const TEMPO_SMOOTHING: f64 = 0.1;
+
+/// Intervals between clocks longer than this, equivalent to 10 beats per minute, mean that the clock
+/// paused rather than slowed.
+const MAX_CLOCK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The tempo and transport of a MIDI clock master, as given by the clock messages it has sent.
+/// Shared between a synthesizer, which updates it, and any other thread.
+#[derive(Debug, Default)]
+pub struct MidiClock {
+    running: AtomicCell<bool>,
+    /// Clocks since the start of the song.
+    position: AtomicCell<u64>,
+    /// Estimated seconds between clocks, or `0.0` if not yet known.
+    period: AtomicCell<f64>,
+    last_clock: AtomicCell<Option<Instant>>,
+    /// Number of `Start` and `Song Position Pointer` messages received.
+    relocations: AtomicCell<u64>,
+    /// Position to which the most recent of them moved.
+    relocated_to: AtomicCell<u64>,
+}
+
+impl MidiClock {
+    /// Returns the tempo of the master in beats per minute, or `None` if it has yet to send enough
+    /// `Timing Clock` messages to tell.
+    pub fn tempo(&self) -> Option<f32> {
+        let period = self.period.load();
+        (period > 0.0).then(|| (60.0 / (period * CLOCKS_PER_BEAT as f64)) as f32)
+    }
+
+    /// Returns `true` between a `Start` or `Continue` message and the next `Stop` message.
+    pub fn is_running(&self) -> bool {
+        self.running.load()
+    }
+
+    /// Returns the number of `Timing Clock` messages received while running since the start of the song,
+    /// as located by `Start` and `Song Position Pointer` messages.
+    pub fn position(&self) -> u64 {
+        self.position.load()
+    }
+
+    /// Returns the position in beats since the start of the song.
+    pub fn beats(&self) -> f64 {
+        self.position() as f64 / CLOCKS_PER_BEAT as f64
+    }
+
+    /// Returns the position in MIDI beats, as in a `Song Position Pointer`.
+    pub fn song_position(&self) -> u16 {
+        (self.position() / CLOCKS_PER_MIDI_BEAT as u64).min(u16::MAX as u64) as u16
+    }
+
+    /// Returns the number of times that a `Start` or `Song Position Pointer` has moved the position, and
+    /// the position to which the most recent one moved it. The next `Timing Clock` after a move plays
+    /// that position, so the clocks before it count as played.
+    pub(crate) fn relocation(&self) -> (u64, u64) {
+        (self.relocations.load(), self.relocated_to.load())
+    }
+
+    fn relocate(&self, position: u64) {
+        self.position.store(position);
+        self.relocated_to.store(position);
+        self.relocations.fetch_add(1);
+    }
+
+    /// Updates the clock from `msg`, received at `time`. Other messages are ignored.
+    pub(crate) fn handle(&self, msg: &MidiMsg, time: Instant) {
+        match msg {
+            MidiMsg::SystemRealTime { msg } => match msg {
+                SystemRealTimeMsg::TimingClock => {
+                    if let Some(last) = self.last_clock.swap(Some(time))
+                        && time > last
+                        && time - last <= MAX_CLOCK_INTERVAL
+                    {
+                        let interval = (time - last).as_secs_f64();
+                        let period = self.period.load();
+                        self.period.store(if period > 0.0 {
+                            period + TEMPO_SMOOTHING * (interval - period)
+                        } else {
+                            interval
+                        });
+                    }
+                    if self.running.load() {
+                        self.position.fetch_add(1);
+                    }
+                }
+                SystemRealTimeMsg::Start => {
+                    self.relocate(0);
+                    self.running.store(true);
+                }
+                SystemRealTimeMsg::Continue => self.running.store(true),
+                SystemRealTimeMsg::Stop => self.running.store(false),
+                _ => {}
+            },
+            MidiMsg::SystemCommon {
+                msg: SystemCommonMsg::SongPosition(beats),
+            } => self.relocate(*beats as u64 * CLOCKS_PER_MIDI_BEAT as u64),
+            _ => {}
+        }
+    }
+}
+
+enum ClockCommand {
+    Tempo(f32),
+    Send(MidiMsg),
+}
+
+/// Starts a thread that acts as a MIDI clock master at `bpm` beats per minute, passing each message it sends
+/// to `send` along with the time at which it is due. `send` might pass them to a MIDI output connection,
+/// or place them in a synthesizer's queue as `SynthMsg` objects.
+///
+/// `Timing Clock` messages are sent continuously, whether or not the transport is running.
+pub fn start_clock_thread<F: FnMut(MidiMsg, Instant) + Send + 'static>(
+    bpm: f32,
+    mut send: F,
+) -> ClockHandle {
+    let commands = Arc::new(MsgQueue::new());
+    let stop = Arc::new(AtomicCell::new(false));
+    let thread_commands = commands.clone();
+    let thread_stop = stop.clone();
+    let thread = std::thread::spawn(move || {
+        let (commands, stop) = (thread_commands, thread_stop);
+        let mut period = clock_period(bpm);
+        let mut next = Instant::now();
+        while !stop.load() {
+            let command = commands.pop_wait_until(&stop, next);
+            let now = Instant::now();
+            while next <= now {
+                send(
+                    MidiMsg::SystemRealTime {
+                        msg: SystemRealTimeMsg::TimingClock,
+                    },
+                    next,
+                );
+                next += period;
+            }
+            match command {
+                Some(ClockCommand::Tempo(bpm)) => period = clock_period(bpm),
+                Some(ClockCommand::Send(msg)) => send(msg, now),
+                None => {}
+            }
+        }
+    });
+    ClockHandle {
+        commands,
+        stop,
+        thread,
+    }
+}
+
+fn clock_period(bpm: f32) -> Duration {
+    Duration::from_secs_f64(60.0 / (bpm.max(1.0) as f64 * CLOCKS_PER_BEAT as f64))
+}
+
+/// A MIDI clock master started by `start_clock_thread()`.
+///
+/// Dropping a `ClockHandle` leaves the clock running.
+pub struct ClockHandle {
+    commands: Arc<MsgQueue<ClockCommand>>,
+    stop: Arc<AtomicCell<bool>>,
+    thread: JoinHandle<()>,
+}
+
+impl ClockHandle {
+    /// Changes the tempo to `bpm` beats per minute, starting with the next `Timing Clock`.
+    pub fn set_tempo(&self, bpm: f32) {
+        self.commands.push(ClockCommand::Tempo(bpm));
+    }
+
+    /// Sends `Start`, telling followers to play from the start of the song.
+    pub fn send_start(&self) {
+        self.send(SystemRealTimeMsg::Start);
+    }
+
+    /// Sends `Stop`, telling followers to pause.
+    pub fn send_stop(&self) {
+        self.send(SystemRealTimeMsg::Stop);
+    }
+
+    /// Sends `Continue`, telling followers to resume from where they paused.
+    pub fn send_continue(&self) {
+        self.send(SystemRealTimeMsg::Continue);
+    }
+
+    /// Sends a `Song Position Pointer`, telling followers to move to the position `midi_beats`
+    /// sixteenth notes into the song. Followers should only receive it while stopped.
+    pub fn send_song_position(&self, midi_beats: u16) {
+        self.commands
+            .push(ClockCommand::Send(MidiMsg::SystemCommon {
+                msg: SystemCommonMsg::SongPosition(midi_beats),
+            }));
+    }
+
+    fn send(&self, msg: SystemRealTimeMsg) {
+        self.commands
+            .push(ClockCommand::Send(MidiMsg::SystemRealTime { msg }));
+    }
+
+    /// Asks the clock to stop sending messages and exit. Unlike `send_stop()`, followers are not told.
+    pub fn stop(&self) {
+        self.stop.store(true);
+        self.commands.wake();
+    }
+
+    /// Waits for the clock's thread to exit after `stop()`.
+    pub fn join(self) {
+        if let Err(panic) = self.thread.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use float_eq::assert_float_eq;
+    use midi_msg::{MidiMsg, SystemCommonMsg, SystemRealTimeMsg};
+
+    use crate::clock::{MidiClock, start_clock_thread};
+
+    fn real_time(msg: SystemRealTimeMsg) -> MidiMsg {
+        MidiMsg::SystemRealTime { msg }
+    }
+
+    fn tick(clock: &MidiClock, time: &mut Instant) {
+        clock.handle(&real_time(SystemRealTimeMsg::TimingClock), *time);
+        *time += Duration::from_millis(20);
+    }
+
+    #[test]
+    fn test_midi_clock_follows_master() {
+        let clock = MidiClock::default();
+        let start = Instant::now();
+        // 24 clocks per beat at 125 beats per minute are 20 ms apart.
+        let mut time = start;
+        tick(&clock, &mut time);
+        assert_eq!(clock.tempo(), None);
+        tick(&clock, &mut time);
+        assert_float_eq!(clock.tempo().unwrap(), 125.0, rmax <= 1e-4);
+        assert_eq!(clock.position(), 0);
+
+        clock.handle(&real_time(SystemRealTimeMsg::Start), time);
+        (0..48).for_each(|_| tick(&clock, &mut time));
+        assert!(clock.is_running());
+        assert_float_eq!(clock.beats(), 2.0, abs <= 1e-9);
+        clock.handle(&real_time(SystemRealTimeMsg::Stop), time);
+        tick(&clock, &mut time);
+        assert_eq!(clock.song_position(), 8);
+
+        clock.handle(
+            &MidiMsg::SystemCommon {
+                msg: SystemCommonMsg::SongPosition(32),
+            },
+            time,
+        );
+        clock.handle(&real_time(SystemRealTimeMsg::Continue), time);
+        (0..6).for_each(|_| tick(&clock, &mut time));
+        assert_eq!(clock.song_position(), 33);
+        assert_float_eq!(clock.tempo().unwrap(), 125.0, rmax <= 1e-4);
+    }
+
+    #[test]
+    fn test_clock_thread() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let thread_sent = sent.clone();
+        let master = start_clock_thread(600.0, move |msg, time| {
+            thread_sent.lock().unwrap().push((msg, time))
+        });
+        master.send_start();
+        std::thread::sleep(Duration::from_millis(50));
+        master.stop();
+        master.join();
+        let sent = sent.lock().unwrap();
+        let follower = MidiClock::default();
+        for (msg, time) in sent.iter() {
+            follower.handle(msg, *time);
+        }
+        assert!(follower.is_running());
+        assert!(follower.position() >= 5);
+        assert_float_eq!(follower.tempo().unwrap(), 600.0, rmax <= 1e-3);
+    }
+}
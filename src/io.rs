@@ -1,35 +1,174 @@
 use anyhow::{anyhow, bail};
-use bare_metal_modulo::*;
 use cpal::{
-    Device, FromSample, Sample, SampleFormat, SizedSample, Stream, StreamConfig,
-    traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize, Device, SampleFormat, StreamConfig, SupportedBufferSize,
+    traits::{DeviceTrait, HostTrait},
 };
 use crossbeam_queue::SegQueue;
 use crossbeam_utils::atomic::AtomicCell;
-use fundsp::{
-    net::Net,
-    prelude::{AudioUnit, FrameAdd, FrameMul},
-    prelude64::{shared, var},
-    shared::Shared,
-};
-use midi_msg::{Channel, ChannelModeMsg, ChannelVoiceMsg, MidiMsg, SystemRealTimeMsg};
+use midi_msg::{ChannelModeMsg, ChannelVoiceMsg, MidiMsg, SystemRealTimeMsg};
+#[cfg(unix)]
+use midir::os::unix::VirtualInput;
 use midir::{Ignore, MidiInput, MidiInputPort};
 use read_input::{InputBuild, shortcut::input};
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    NUM_MIDI_VALUES, SharedMidiState, SynthFunc, note_velocity_from, sound_builders::ProgramTable,
+    arpeggiator::{ArpEngine, Arpeggiator},
+    clock::MidiClock,
+    note_velocity_from,
+    sinks::{AudioSink, CpalSink},
+    sound_builders::SharedProgramTable,
+    synth::{StereoPlayer, Transposition},
+    tunings::{KeyTable, Tuning, adaptive::AdaptiveJust, mts::parse_tuning_sysex},
+    velocity::VelocityCurve,
+    zones::Zone,
 };
 
+/// A queue of messages shared between threads.
+///
+/// Any number of threads may `push()` messages. A thread waiting for a message with `pop_wait()`
+/// sleeps until one arrives instead of spinning, so idle synthesizers do not occupy a CPU core.
+pub struct MsgQueue<T> {
+    queue: SegQueue<T>,
+    lock: Mutex<()>,
+    ready: Condvar,
+}
+
+impl<T> MsgQueue<T> {
+    /// Creates an empty queue.
+    pub fn new() -> Self {
+        Self {
+            queue: SegQueue::new(),
+            lock: Mutex::new(()),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Adds `msg` to the back of the queue, waking any thread waiting in `pop_wait()`.
+    pub fn push(&self, msg: T) {
+        self.queue.push(msg);
+        self.wake();
+    }
+
+    /// Wakes every thread waiting in `pop_wait()` or one of its variants.
+    pub(crate) fn wake(&self) {
+        // Acquiring the lock ensures that a waiting thread is either about to check the queue
+        // again or already waiting on `ready`, so the notification cannot be lost.
+        drop(self.lock.lock().unwrap());
+        self.ready.notify_all();
+    }
+
+    /// Removes and returns the message at the front of the queue, or `None` if it is empty.
+    /// Never blocks.
+    pub fn pop(&self) -> Option<T> {
+        self.queue.pop()
+    }
+
+    /// Removes and returns the message at the front of the queue, sleeping until one
+    /// is available if the queue is empty.
+    pub fn pop_wait(&self) -> T {
+        loop {
+            if let Some(msg) = self.queue.pop() {
+                return msg;
+            }
+            let guard = self.lock.lock().unwrap();
+            if let Some(msg) = self.queue.pop() {
+                return msg;
+            }
+            drop(self.ready.wait(guard).unwrap());
+        }
+    }
+
+    /// Behaves like `pop_wait()`, but returns `None` instead once `stop` holds `true`.
+    /// Whoever stores `true` in `stop` must then call `wake()`.
+    pub(crate) fn pop_wait_unless(&self, stop: &AtomicCell<bool>) -> Option<T> {
+        self.pop_wait_or(|| stop.load(), None)
+    }
+
+    /// Behaves like `pop_wait_unless()`, but also returns `None` once `deadline` has passed.
+    pub(crate) fn pop_wait_until(&self, stop: &AtomicCell<bool>, deadline: Instant) -> Option<T> {
+        self.pop_wait_or(|| stop.load(), Some(deadline))
+    }
+
+    /// Behaves like `pop_wait_until()`, but returns `None` once `interrupted()` returns `true` rather than
+    /// once a flag holds `true`, and waits indefinitely if `deadline` is `None`. Whatever makes
+    /// `interrupted()` return `true` must then call `wake()`.
+    pub(crate) fn pop_wait_or<F: Fn() -> bool>(
+        &self,
+        interrupted: F,
+        deadline: Option<Instant>,
+    ) -> Option<T> {
+        loop {
+            if interrupted() {
+                return None;
+            }
+            if let Some(msg) = self.queue.pop() {
+                return Some(msg);
+            }
+            let guard = self.lock.lock().unwrap();
+            if interrupted() {
+                return None;
+            }
+            if let Some(msg) = self.queue.pop() {
+                return Some(msg);
+            }
+            match deadline {
+                None => drop(self.ready.wait(guard).unwrap()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    drop(self.ready.wait_timeout(guard, deadline - now).unwrap());
+                }
+            }
+        }
+    }
+
+    /// Returns the number of messages in the queue.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns `true` if the queue holds no messages.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl<T> Default for MsgQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Clone, Debug)]
 /// Packages a [`MidiMsg`](https://crates.io/crates/midi-msg) with a designated `Speaker` to output the sound
 /// corresponding to the message.
+///
+/// If `time` is known, the sound is rendered at the audio frame corresponding to that moment, offset by a
+/// constant latency of one audio buffer. Otherwise, it is rendered as if it had been received when the output
+/// thread first examined it.
 pub struct SynthMsg {
     pub msg: MidiMsg,
     pub speaker: Speaker,
+    pub time: Option<Instant>,
 }
 
 impl SynthMsg {
+    /// Packages `msg` for `speaker` without a timestamp.
+    pub fn new(msg: MidiMsg, speaker: Speaker) -> Self {
+        Self {
+            msg,
+            speaker,
+            time: None,
+        }
+    }
+
     /// Returns MIDI `All Notes Off` message. This releases all current sounds.
     pub fn all_notes_off(speaker: Speaker) -> Self {
         Self::mode_msg(ChannelModeMsg::AllNotesOff, speaker)
@@ -41,13 +180,13 @@ impl SynthMsg {
     }
 
     fn mode_msg(msg: ChannelModeMsg, speaker: Speaker) -> Self {
-        Self {
-            msg: MidiMsg::ChannelMode {
+        Self::new(
+            MidiMsg::ChannelMode {
                 channel: midi_msg::Channel::Ch1,
                 msg,
             },
             speaker,
-        }
+        )
     }
 
     /// Returns MIDI `System Reset` message.
@@ -56,21 +195,18 @@ impl SynthMsg {
     }
 
     fn system_real_time_msg(msg: SystemRealTimeMsg, speaker: Speaker) -> Self {
-        Self {
-            msg: MidiMsg::SystemRealTime { msg },
-            speaker,
-        }
+        Self::new(MidiMsg::SystemRealTime { msg }, speaker)
     }
 
     /// Returns MIDI `Program Change` message. This selects the synthesizer sound with the given index.
     pub fn program_change(program: u8, speaker: Speaker) -> Self {
-        Self {
-            msg: MidiMsg::ChannelVoice {
+        Self::new(
+            MidiMsg::ChannelVoice {
                 channel: midi_msg::Channel::Ch1,
                 msg: ChannelVoiceMsg::ProgramChange { program },
             },
             speaker,
-        }
+        )
     }
 
     /// Returns MIDI note and velocity information if pertinent
@@ -82,91 +218,214 @@ impl SynthMsg {
 /// Starts a thread that monitors MIDI input events from the source specified by `in_port`. Each message received is
 /// stored in a `SynthMsg` object and placed in the `midi_msgs` queue.
 ///
-/// If `true` is stored in `quit`, the thread exits and it sends a MIDI `SystemReset` message.
-/// If `print_incoming_msg` is `true`, each incoming MIDI message will be printed to the console.
+/// `InputHandle::stop()` on the returned handle makes the thread exit and send a MIDI `SystemReset` message.
 ///
 /// The functions `get_first_midi_device()` and `choose_midi_device()` are examples of how to
 /// select a value for `in_port`.
 pub fn start_input_thread(
-    midi_msgs: Arc<SegQueue<SynthMsg>>,
+    midi_msgs: Arc<MsgQueue<SynthMsg>>,
     midi_in: MidiInput,
     in_port: MidiInputPort,
-    quit: Arc<AtomicCell<bool>>,
-) {
+) -> InputHandle {
     start_generic_input_thread(
-        |msg| SynthMsg {
+        |msg, time| SynthMsg {
             msg,
             speaker: Speaker::Both,
+            time: Some(time),
         },
         SynthMsg::system_reset(Speaker::Both),
         midi_msgs,
         midi_in,
-        in_port,
-        quit,
+        InputSource::Port(in_port),
     )
 }
 
 /// Starts a thread that monitors MIDI input events from the source specified by `in_port`. Each `MidiMsg` object
 /// received is placed in the `midi_msgs` queue.
 ///
-/// If `true` is stored in `quit`, the thread exits and it sends a MIDI `SystemReset` message.
-/// If `print_incoming_msg` is `true`, each incoming MIDI message will be printed to the console.
+/// `InputHandle::stop()` on the returned handle makes the thread exit and send a MIDI `SystemReset` message.
 ///
 /// The functions `get_first_midi_device()` and `choose_midi_device()` are examples of how to
 /// select a value for `in_port`.
 pub fn start_midi_input_thread(
-    midi_msgs: Arc<SegQueue<MidiMsg>>,
+    midi_msgs: Arc<MsgQueue<MidiMsg>>,
     midi_in: MidiInput,
     in_port: MidiInputPort,
-    quit: Arc<AtomicCell<bool>>,
-) {
+) -> InputHandle {
+    start_generic_input_thread(
+        |msg, _| msg,
+        MidiMsg::SystemRealTime {
+            msg: SystemRealTimeMsg::SystemReset,
+        },
+        midi_msgs,
+        midi_in,
+        InputSource::Port(in_port),
+    )
+}
+
+/// Starts a thread that creates a virtual MIDI input port named `port_name`. Other software on the same
+/// machine, such as DAWs and sequencers, can then connect to it like any other software instrument.
+/// Each message received is stored in a `SynthMsg` object and placed in the `midi_msgs` queue.
+///
+/// `InputHandle::stop()` on the returned handle makes the thread close the port, send a MIDI `SystemReset`
+/// message, and exit.
+///
+/// Virtual ports are available on every platform [midir](https://crates.io/crates/midir) supports except Windows.
+#[cfg(unix)]
+pub fn start_virtual_input_thread(
+    midi_msgs: Arc<MsgQueue<SynthMsg>>,
+    midi_in: MidiInput,
+    port_name: &str,
+) -> InputHandle {
+    start_generic_input_thread(
+        |msg, time| SynthMsg {
+            msg,
+            speaker: Speaker::Both,
+            time: Some(time),
+        },
+        SynthMsg::system_reset(Speaker::Both),
+        midi_msgs,
+        midi_in,
+        InputSource::Virtual(port_name.to_owned()),
+    )
+}
+
+/// Starts a thread that creates a virtual MIDI input port named `port_name`. Other software on the same
+/// machine, such as DAWs and sequencers, can then connect to it like any other software instrument.
+/// Each `MidiMsg` object received is placed in the `midi_msgs` queue.
+///
+/// `InputHandle::stop()` on the returned handle makes the thread close the port, send a MIDI `SystemReset`
+/// message, and exit.
+///
+/// Virtual ports are available on every platform [midir](https://crates.io/crates/midir) supports except Windows.
+#[cfg(unix)]
+pub fn start_virtual_midi_input_thread(
+    midi_msgs: Arc<MsgQueue<MidiMsg>>,
+    midi_in: MidiInput,
+    port_name: &str,
+) -> InputHandle {
     start_generic_input_thread(
-        |msg| msg,
+        |msg, _| msg,
         MidiMsg::SystemRealTime {
             msg: SystemRealTimeMsg::SystemReset,
         },
         midi_msgs,
         midi_in,
-        in_port,
-        quit,
+        InputSource::Virtual(port_name.to_owned()),
     )
 }
 
-fn start_generic_input_thread<M: Send + 'static, F: Send + 'static + Fn(MidiMsg) -> M>(
+/// Where an input thread obtains its MIDI messages.
+enum InputSource {
+    Port(MidiInputPort),
+    #[cfg(unix)]
+    Virtual(String),
+}
+
+fn start_generic_input_thread<M: Send + 'static, F: Send + 'static + Fn(MidiMsg, Instant) -> M>(
     encoder: F,
     reset: M,
-    midi_msgs: Arc<SegQueue<M>>,
+    midi_msgs: Arc<MsgQueue<M>>,
     midi_in: MidiInput,
-    in_port: MidiInputPort,
-    quit: Arc<AtomicCell<bool>>,
-) {
-    std::thread::spawn(move || {
-        let _conn_in = midi_in
-            .connect(
-                &in_port,
-                "midir-read-input",
-                input_callback(encoder, midi_msgs.clone()),
-                (),
-            )
-            .unwrap();
-        while !quit.load() {}
+    source: InputSource,
+) -> InputHandle {
+    let quit = Arc::new(AtomicCell::new(false));
+    let thread_quit = quit.clone();
+    let thread = std::thread::spawn(move || {
+        let quit = thread_quit;
+        let callback = input_callback(encoder, midi_msgs.clone());
+        let _conn_in = match source {
+            InputSource::Port(in_port) => {
+                midi_in.connect(&in_port, "midir-read-input", callback, ())
+            }
+            #[cfg(unix)]
+            InputSource::Virtual(port_name) => midi_in.create_virtual(&port_name, callback, ()),
+        }
+        .unwrap();
+        // `InputHandle::stop()` unparks the thread after storing `true` in `quit`. Parking may also
+        // return spuriously, so the flag is checked again each time.
+        while !quit.load() {
+            std::thread::park();
+        }
         midi_msgs.push(reset);
-        quit.store(false);
     });
+    InputHandle { quit, thread }
 }
 
-fn input_callback<M: Send + 'static, F: Send + 'static + Fn(MidiMsg) -> M>(
+/// An input thread started by `start_input_thread()` or one of its variants.
+///
+/// Dropping an `InputHandle` leaves its thread running.
+pub struct InputHandle {
+    quit: Arc<AtomicCell<bool>>,
+    thread: JoinHandle<()>,
+}
+
+impl InputHandle {
+    /// Asks the thread to close its MIDI port, send a MIDI `SystemReset` message, and exit.
+    pub fn stop(&self) {
+        self.quit.store(true);
+        self.thread.thread().unpark();
+    }
+
+    /// Waits for the thread to exit. Once this returns, its MIDI port is closed, so that a new input
+    /// thread may open it.
+    pub fn join(self) {
+        join_thread(self.thread);
+    }
+}
+
+fn join_thread(thread: JoinHandle<()>) {
+    if let Err(panic) = thread.join() {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+fn input_callback<M: Send + 'static, F: Send + 'static + Fn(MidiMsg, Instant) -> M>(
     encoder: F,
-    midi_msgs: Arc<SegQueue<M>>,
-) -> impl Fn(u64, &[u8], &mut ()) {
-    move |_stamp, message, _| {
-        let (msg, _len) = MidiMsg::from_midi(&message).unwrap();
-        midi_msgs.push(encoder(msg));
+    midi_msgs: Arc<MsgQueue<M>>,
+) -> impl FnMut(u64, &[u8], &mut ()) {
+    let mut clock = StampClock::default();
+    move |stamp, message, _| {
+        let msg = match MidiMsg::from_midi(message) {
+            Ok((msg, _len)) => Some(msg),
+            Err(_) => parse_tuning_sysex(message),
+        };
+        // Messages that cannot be decoded cannot be played either, so they are dropped.
+        if let Some(msg) = msg {
+            midi_msgs.push(encoder(msg, clock.instant(stamp)));
+        }
     }
 }
 
-/// Plays sounds according to instructions received in the `midi_msgs` queue. Synthesizer sounds may be selected with
-/// MIDI `Program Change` messages that reference sounds stored in `program_table`.
+/// Largest discrepancy tolerated between `Instant::now()` and a time derived from midir timestamps
+/// before `StampClock` resynchronizes.
+const MAX_STAMP_DRIFT: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+/// Converts the microsecond timestamps that midir attaches to incoming messages into `Instant`s.
+struct StampClock {
+    origin: Option<(u64, Instant)>,
+}
+
+impl StampClock {
+    fn instant(&mut self, stamp: u64) -> Instant {
+        let now = Instant::now();
+        if let Some((origin_stamp, origin)) = self.origin
+            && let Some(elapsed) = stamp.checked_sub(origin_stamp)
+            && let time = origin + Duration::from_micros(elapsed)
+            && time <= now
+            && now - time < MAX_STAMP_DRIFT
+        {
+            return time;
+        }
+        self.origin = Some((stamp, now));
+        now
+    }
+}
+
+/// Configures and starts a synthesizer that plays sounds according to instructions received in a `MsgQueue`.
+/// Synthesizer sounds may be selected with MIDI `Program Change` messages that reference sounds stored in the
+/// program table. Edits to the program table take effect upon the next `Program Change`.
 ///
 /// The constant value `N` is the number of distinct sounds it can emit. Each MIDI `Note On` message uses one distinct
 /// sound. When a number of `Note On` messages greater than `N` has been received, the sound used by the oldest `Note On`
@@ -174,77 +433,371 @@ fn input_callback<M: Send + 'static, F: Send + 'static + Fn(MidiMsg) -> M>(
 ///
 /// Setting `N = 1` yields a monophonic synthesizer. Setting `N = 10` should suffice for most purposes.
 ///
-/// If a `SystemReset` MIDI message is received, the thread exits.
+/// By default, the synthesizer uses equal temperament, plays through the default audio output device, and
+/// creates a new queue of `SynthMsg` objects. If a `SystemReset` MIDI message is received, it stops.
+///
+/// ```no_run
+/// use std::sync::Arc;
+/// use midi_fundsp::{io::SynthBuilder, sound_builders::SharedProgramTable, sounds::options};
+///
+/// let synth = SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+///     .start()
+///     .unwrap();
+/// ```
+pub struct SynthBuilder<const N: usize> {
+    program_table: Arc<SharedProgramTable>,
+    tuning: Option<Arc<dyn Tuning>>,
+    adaptive: Option<AdaptiveJust>,
+    zones: Option<Vec<Zone>>,
+    velocity_curve: VelocityCurve,
+    arpeggiator: Option<Arpeggiator>,
+    output: SynthOutput,
+    input: SynthInput,
+}
+
+enum SynthOutput {
+    Device(OutputConfig),
+    Sink(Box<dyn AudioSink + Send>),
+}
+
+enum ResolvedSynthOutput {
+    Device(ResolvedOutput),
+    Sink(Box<dyn AudioSink + Send>),
+}
+
+enum SynthInput {
+    New,
+    Synth(Arc<MsgQueue<SynthMsg>>),
+    Midi(Arc<MsgQueue<MidiMsg>>),
+}
+
+impl<const N: usize> SynthBuilder<N> {
+    /// Creates a builder for a synthesizer playing sounds from `program_table`.
+    pub fn new(program_table: Arc<SharedProgramTable>) -> Self {
+        Self {
+            program_table,
+            tuning: None,
+            adaptive: None,
+            zones: None,
+            velocity_curve: VelocityCurve::default(),
+            arpeggiator: None,
+            output: SynthOutput::Device(OutputConfig::default()),
+            input: SynthInput::New,
+        }
+    }
+
+    /// Converts MIDI pitches (0-127) to frequencies with `midi_to_hz()`. To represent
+    /// an alternative tuning system, pass in an appropriate function.
+    pub fn with_midi_to_hz(self, midi_to_hz: fn(f32) -> f32) -> Self {
+        self.with_tuning(Arc::new(midi_to_hz))
+    }
+
+    /// Converts MIDI pitches (0-127) to frequencies with `tuning`, such as a `ScalaTuning`.
+    pub fn with_tuning(mut self, tuning: Arc<dyn Tuning>) -> Self {
+        self.tuning = Some(tuning);
+        self
+    }
+
+    /// Retunes held notes by `adaptive` as the harmony changes, with the tuning given to `with_tuning()`
+    /// deciding the frequency of each chord's root.
+    pub fn with_adaptive_tuning(mut self, adaptive: AdaptiveJust) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Converts `Note On` velocities into volumes with `curve`, except in zones with curves of their own.
+    /// Defaults to `VelocityCurve::Linear`.
+    pub fn with_velocity_curve(mut self, curve: VelocityCurve) -> Self {
+        self.velocity_curve = curve;
+        self
+    }
+
+    /// Plays sounds through `zones`, which split the keyboard or layer sounds, rather than through
+    /// one part for each `Speaker`.
+    pub fn with_zones(mut self, zones: Vec<Zone>) -> Self {
+        self.zones = Some(zones);
+        self
+    }
+
+    /// Passes incoming notes through `arpeggiator`, which plays the notes of held chords one at a time.
+    pub fn with_arpeggiator(mut self, arpeggiator: Arpeggiator) -> Self {
+        self.arpeggiator = Some(arpeggiator);
+        self
+    }
+
+    /// Plays through the device and with the sample rate and buffer size specified by `output`.
+    pub fn with_output(mut self, output: OutputConfig) -> Self {
+        self.output = SynthOutput::Device(output);
+        self
+    }
+
+    /// Sends audio to `sink` rather than to an audio output device. This enables a synthesizer to run where
+    /// no audio hardware is available, such as on servers, in tests, and in containers.
+    pub fn with_sink<S: AudioSink + Send + 'static>(mut self, sink: S) -> Self {
+        self.output = SynthOutput::Sink(Box::new(sink));
+        self
+    }
+
+    /// Receives instructions from `midi_msgs`, such as one filled by `start_input_thread()`.
+    pub fn with_messages(mut self, midi_msgs: Arc<MsgQueue<SynthMsg>>) -> Self {
+        self.input = SynthInput::Synth(midi_msgs);
+        self
+    }
+
+    /// Receives instructions from `midi_msgs`, such as one filled by `start_midi_input_thread()`.
+    /// Each message is played through both speakers.
+    pub fn with_midi_messages(mut self, midi_msgs: Arc<MsgQueue<MidiMsg>>) -> Self {
+        self.input = SynthInput::Midi(midi_msgs);
+        self
+    }
+
+    /// Starts the synthesizer on a thread of its own.
+    ///
+    /// Returns an error without starting the thread if the audio output device cannot satisfy
+    /// the configuration given to `with_output()`, or if a zone given to `with_zones()` refers to
+    /// a program not in the program table.
+    pub fn start(self) -> anyhow::Result<SynthHandle> {
+        let mut player = match self.zones {
+            Some(zones) => StereoPlayer::<N>::with_zones(self.program_table, zones)?,
+            None => StereoPlayer::<N>::new(self.program_table),
+        };
+        if let Some(tuning) = self.tuning {
+            player.set_tuning(tuning.as_ref());
+        }
+        player.set_adaptive_tuning(self.adaptive);
+        player.set_velocity_curve(self.velocity_curve);
+        let key_tables = player.key_tables();
+        let transposition = player.transposition();
+        let clock = player.clock();
+        let output = match self.output {
+            SynthOutput::Device(output) => ResolvedSynthOutput::Device(output.resolve()?),
+            SynthOutput::Sink(sink) => ResolvedSynthOutput::Sink(sink),
+        };
+        let stop = Arc::new(AtomicCell::new(false));
+        let mut threads = vec![];
+        let mut relayed_msgs = None;
+        let midi_msgs = match self.input {
+            SynthInput::New => Arc::new(MsgQueue::new()),
+            SynthInput::Synth(midi_msgs) => midi_msgs,
+            SynthInput::Midi(incoming) => {
+                relayed_msgs = Some(incoming.clone());
+                let relay_out = Arc::new(MsgQueue::new());
+                let relay_in = relay_out.clone();
+                let stop = stop.clone();
+                threads.push(std::thread::spawn(move || {
+                    while let Some(msg) = incoming.pop_wait_unless(&stop) {
+                        let reset = msg
+                            == MidiMsg::SystemRealTime {
+                                msg: SystemRealTimeMsg::SystemReset,
+                            };
+                        relay_out.push(SynthMsg::new(msg, Speaker::Both));
+                        if reset {
+                            return;
+                        }
+                    }
+                }));
+                relay_in
+            }
+        };
+        let player_msgs = match self.arpeggiator {
+            None => midi_msgs.clone(),
+            Some(arpeggiator) => {
+                let arp_in = midi_msgs.clone();
+                let arp_out = Arc::new(MsgQueue::new());
+                let player_msgs = arp_out.clone();
+                let stop = stop.clone();
+                threads.push(std::thread::spawn(move || {
+                    run_arpeggiator(ArpEngine::new(arpeggiator), &arp_in, &arp_out, &stop)
+                }));
+                player_msgs
+            }
+        };
+        let woken_msgs = player_msgs.clone();
+        let player_stop = stop.clone();
+        threads.push(std::thread::spawn(move || {
+            // A cpal stream cannot move between threads, so the device is opened here.
+            let result = match output {
+                ResolvedSynthOutput::Device(output) => {
+                    let mut sink = CpalSink::from_resolved(output);
+                    player.run_output(player_msgs, &mut sink, &player_stop)
+                }
+                ResolvedSynthOutput::Sink(mut sink) => {
+                    player.run_output(player_msgs, sink.as_mut(), &player_stop)
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("Error on output: {err}");
+            }
+        }));
+        Ok(SynthHandle {
+            midi_msgs,
+            relayed_msgs,
+            player_msgs: woken_msgs,
+            stop,
+            threads,
+            key_tables,
+            transposition,
+            clock,
+        })
+    }
+}
+
+/// A synthesizer started by `SynthBuilder::start()`.
+///
+/// Dropping a `SynthHandle` leaves the synthesizer running until it receives a MIDI `SystemReset` message.
+pub struct SynthHandle {
+    midi_msgs: Arc<MsgQueue<SynthMsg>>,
+    relayed_msgs: Option<Arc<MsgQueue<MidiMsg>>>,
+    player_msgs: Arc<MsgQueue<SynthMsg>>,
+    stop: Arc<AtomicCell<bool>>,
+    threads: Vec<JoinHandle<()>>,
+    key_tables: Vec<Arc<KeyTable>>,
+    transposition: Arc<Transposition>,
+    clock: Arc<MidiClock>,
+}
+
+impl SynthHandle {
+    /// Returns the queue from which the synthesizer receives instructions, for sharing with other threads.
+    pub fn messages(&self) -> Arc<MsgQueue<SynthMsg>> {
+        self.midi_msgs.clone()
+    }
+
+    /// Sends `msg` to the synthesizer.
+    pub fn send(&self, msg: SynthMsg) {
+        self.midi_msgs.push(msg);
+    }
+
+    /// Retunes every key according to `tuning` while the synthesizer plays, taking effect upon the
+    /// next `Note On`. Subsequent changes to the state of `tuning` take effect only if it is passed here again.
+    pub fn set_tuning(&self, tuning: &dyn Tuning) {
+        for keys in self.key_tables.iter() {
+            keys.retune_all(tuning);
+        }
+    }
+
+    /// Transposes notes played through `speaker` by `semitones` while the synthesizer plays, taking effect
+    /// upon the next `Note On`. Notes already playing are released normally by their `Note Off`.
+    /// The transposition for `Speaker::Both` applies to both speakers, in addition to their own.
+    pub fn set_transpose(&self, speaker: Speaker, semitones: i8) {
+        self.transposition.set_semitones(speaker, semitones);
+    }
+
+    /// Shifts notes played through `speaker` by `octaves` while the synthesizer plays, taking effect
+    /// upon the next `Note On`. The shift for `Speaker::Both` applies to both speakers, in addition to their own.
+    pub fn set_octave_shift(&self, speaker: Speaker, octaves: i8) {
+        self.transposition.set_octaves(speaker, octaves);
+    }
+
+    /// Returns the `MidiClock` following the tempo and transport of any MIDI clock master sending to
+    /// the synthesizer, so that tempo-synced features can lock to it.
+    pub fn clock(&self) -> Arc<MidiClock> {
+        self.clock.clone()
+    }
+
+    /// Asks the synthesizer to stop. Messages still in its queue remain there unplayed.
+    pub fn stop(&self) {
+        self.stop.store(true);
+        self.midi_msgs.wake();
+        self.player_msgs.wake();
+        if let Some(relayed_msgs) = &self.relayed_msgs {
+            relayed_msgs.wake();
+        }
+    }
+
+    /// Waits for all of the synthesizer's threads to exit, whether due to `stop()` or to a MIDI
+    /// `SystemReset` message. Once this returns, its audio output device has been released.
+    pub fn join(self) {
+        for thread in self.threads {
+            join_thread(thread);
+        }
+    }
+}
+
+/// Relays messages from `incoming` to `outgoing` through `arp`, waking in time for each of its steps,
+/// until `stop` holds `true` or a MIDI `SystemReset` message passes through.
+fn run_arpeggiator(
+    mut arp: ArpEngine,
+    incoming: &MsgQueue<SynthMsg>,
+    outgoing: &MsgQueue<SynthMsg>,
+    stop: &AtomicCell<bool>,
+) {
+    let mut out = vec![];
+    while !stop.load() {
+        let msg = match arp.next_deadline() {
+            Some(deadline) => incoming.pop_wait_until(stop, deadline),
+            None => incoming.pop_wait_unless(stop),
+        };
+        let now = Instant::now();
+        let mut reset = false;
+        if let Some(msg) = msg {
+            let time = msg.time.unwrap_or(now);
+            reset = msg.msg
+                == MidiMsg::SystemRealTime {
+                    msg: SystemRealTimeMsg::SystemReset,
+                };
+            arp.tick(time, &mut out);
+            arp.handle(msg, time, &mut out);
+        }
+        arp.tick(now, &mut out);
+        for msg in out.drain(..) {
+            outgoing.push(msg);
+        }
+        if reset {
+            return;
+        }
+    }
+}
+
+/// Plays sounds according to instructions received in the `midi_msgs` queue, as described for `SynthBuilder`.
+/// Equivalent to `SynthBuilder::<N>::new(program_table).with_messages(midi_msgs).start()`,
+/// except that errors are reported on the console.
+#[deprecated(since = "0.8.0", note = "use `SynthBuilder` instead")]
 pub fn start_output_thread<const N: usize>(
-    midi_msgs: Arc<SegQueue<SynthMsg>>,
-    program_table: Arc<Mutex<ProgramTable>>,
+    midi_msgs: Arc<MsgQueue<SynthMsg>>,
+    program_table: Arc<SharedProgramTable>,
 ) {
-    std::thread::spawn(move || {
-        let mut player = StereoPlayer::<N>::new(program_table);
-        player.run_output(midi_msgs).unwrap();
-    });
+    report_start_error(
+        SynthBuilder::<N>::new(program_table)
+            .with_messages(midi_msgs)
+            .start(),
+    );
 }
 
-/// Plays sounds according to `MidiMsg` objects received in the `midi_msgs` queue. Synthesizer sounds may be selected with
-/// MIDI `Program Change` messages that reference sounds stored in `program_table`.
-///
-/// The constant value `N` is the number of distinct sounds it can emit. Each MIDI `Note On` message uses one distinct
-/// sound. When a number of `Note On` messages greater than `N` has been received, the sound used by the oldest `Note On`
-/// message is reused for the new `Note On` message.
-///
-/// Setting `N = 1` yields a monophonic synthesizer. Setting `N = 10` should suffice for most purposes.
-///
-/// If a `SystemReset` MIDI message is received, the thread exits.
+/// Plays sounds according to `MidiMsg` objects received in the `midi_msgs` queue, as described for `SynthBuilder`.
+/// Equivalent to `SynthBuilder::<N>::new(program_table).with_midi_messages(midi_msgs).start()`,
+/// except that errors are reported on the console.
+#[deprecated(since = "0.8.0", note = "use `SynthBuilder` instead")]
 pub fn start_midi_output_thread<const N: usize>(
-    midi_msgs: Arc<SegQueue<MidiMsg>>,
-    program_table: Arc<Mutex<ProgramTable>>,
+    midi_msgs: Arc<MsgQueue<MidiMsg>>,
+    program_table: Arc<SharedProgramTable>,
 ) {
-    inner_start_output_thread(midi_msgs, StereoPlayer::<N>::new(program_table));
+    report_start_error(
+        SynthBuilder::<N>::new(program_table)
+            .with_midi_messages(midi_msgs)
+            .start(),
+    );
 }
 
-/// Plays sounds according to `MidiMsg` objects received in the `midi_msgs` queue. Synthesizer sounds may be selected with
-/// MIDI `Program Change` messages that reference sounds stored in `program_table`.
-///
-/// The function `midi_to_hz()` converts MIDI pitches (0-127) to frequencies. To represent
-/// an alternative tuning system, pass in an appropriate function.
-///
-/// The constant value `N` is the number of distinct sounds it can emit. Each MIDI `Note On` message uses one distinct
-/// sound. When a number of `Note On` messages greater than `N` has been received, the sound used by the oldest `Note On`
-/// message is reused for the new `Note On` message.
-///
-/// Setting `N = 1` yields a monophonic synthesizer. Setting `N = 10` should suffice for most purposes.
-///
-/// If a `SystemReset` MIDI message is received, the thread exits.
+/// Behaves like `start_midi_output_thread()`, but converts MIDI pitches (0-127) to frequencies with `midi_to_hz()`.
+/// Equivalent to building with `SynthBuilder::with_midi_messages()` and `SynthBuilder::with_midi_to_hz()`.
+#[deprecated(since = "0.8.0", note = "use `SynthBuilder` instead")]
 pub fn start_midi_output_thread_alt_tuning<const N: usize>(
-    midi_msgs: Arc<SegQueue<MidiMsg>>,
-    program_table: Arc<Mutex<ProgramTable>>,
+    midi_msgs: Arc<MsgQueue<MidiMsg>>,
+    program_table: Arc<SharedProgramTable>,
     midi_to_hz: fn(f32) -> f32,
 ) {
-    let mut player = StereoPlayer::<N>::new(program_table);
-    player.set_midi_to_hz(midi_to_hz);
-    inner_start_output_thread(midi_msgs, player);
+    report_start_error(
+        SynthBuilder::<N>::new(program_table)
+            .with_midi_messages(midi_msgs)
+            .with_midi_to_hz(midi_to_hz)
+            .start(),
+    );
 }
 
-fn inner_start_output_thread<const N: usize>(
-    midi_msgs: Arc<SegQueue<MidiMsg>>,
-    mut player: StereoPlayer<N>,
-) {
-    let relay_out = Arc::new(SegQueue::new());
-    let relay_in = relay_out.clone();
-    std::thread::spawn(move || {
-        loop {
-            if let Some(msg) = midi_msgs.pop() {
-                relay_out.push(SynthMsg {
-                    msg,
-                    speaker: Speaker::Both,
-                })
-            }
-        }
-    });
-
-    std::thread::spawn(move || {
-        player.run_output(relay_in).unwrap();
-    });
+fn report_start_error(result: anyhow::Result<SynthHandle>) {
+    if let Err(err) = result {
+        eprintln!("Error on output: {err}");
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -262,132 +815,135 @@ impl Speaker {
     }
 }
 
-struct StereoPlayer<const N: usize> {
-    sounds: [MonoPlayer<N>; 2],
+#[derive(Clone, Default)]
+/// Specifies the audio output device and stream settings used by an output thread. Any setting
+/// left as `None` takes the device's default.
+pub struct OutputConfig {
+    /// Device to play through. The functions `get_default_output_device()` and `choose_output_device()`
+    /// are examples of how to select one.
+    pub device: Option<Device>,
+    /// Sample rate in Hz.
+    pub sample_rate: Option<u32>,
+    /// Number of frames per audio buffer. Smaller buffers reduce latency, but buffers too small for the
+    /// computer to fill in time will produce audible glitches.
+    pub buffer_size: Option<u32>,
 }
 
-impl<const N: usize> StereoPlayer<N> {
-    fn new(program_table: Arc<Mutex<ProgramTable>>) -> Self {
-        let sounds = [
-            MonoPlayer::<N>::new(program_table.clone()),
-            MonoPlayer::<N>::new(program_table),
-        ];
-        Self { sounds }
+impl OutputConfig {
+    /// Returns a copy of this configuration that plays through `device`.
+    pub fn with_device(mut self, device: Device) -> Self {
+        self.device = Some(device);
+        self
     }
 
-    fn set_midi_to_hz(&mut self, midi_to_hz: fn(f32) -> f32) {
-        for i in 0..self.sounds.len() {
-            self.sounds[i].set_midi_to_hz(midi_to_hz);
-        }
+    /// Returns a copy of this configuration that requests `sample_rate`.
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
     }
 
-    fn sound(&self) -> Net {
-        Net::stack(
-            self.sounds[Speaker::Left.i()].sound(),
-            self.sounds[Speaker::Right.i()].sound(),
-        )
+    /// Returns a copy of this configuration that requests buffers of `buffer_size` frames.
+    pub fn with_buffer_size(mut self, buffer_size: u32) -> Self {
+        self.buffer_size = Some(buffer_size);
+        self
     }
 
-    fn run_output(&mut self, midi_msgs: Arc<SegQueue<SynthMsg>>) -> anyhow::Result<()> {
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .ok_or(anyhow!("failed to find a default output device"))?;
-        let config = device.default_output_config()?;
-        match config.sample_format() {
-            SampleFormat::F32 => self.run_synth::<f32>(midi_msgs, device, config.into()),
-            SampleFormat::I16 => self.run_synth::<i16>(midi_msgs, device, config.into()),
-            SampleFormat::U16 => self.run_synth::<u16>(midi_msgs, device, config.into()),
-            sample_format => panic!("Unsupported sample format '{sample_format}'"),
-        }
-    }
-
-    fn decode(&mut self, speaker: Speaker, msg: &MidiMsg) -> Option<RelayedMessage> {
-        match speaker {
-            Speaker::Left | Speaker::Right => self.sounds[speaker.i()].decode(msg),
-            Speaker::Both => {
-                let mut result = None;
-                for sound in self.sounds.iter_mut() {
-                    result = result.or(sound.decode(msg));
-                }
-                result
+    /// Determines the device, sample format, and stream configuration to use, returning an error
+    /// if the device cannot satisfy the requested settings.
+    pub(crate) fn resolve(&self) -> anyhow::Result<ResolvedOutput> {
+        let device = match &self.device {
+            Some(device) => device.clone(),
+            None => default_output_device()?,
+        };
+        let default_config = device.default_output_config()?;
+        let supported = match self.sample_rate {
+            None => default_config,
+            Some(sample_rate) if sample_rate == default_config.sample_rate() => default_config,
+            Some(sample_rate) => {
+                let mut candidates = device
+                    .supported_output_configs()?
+                    .filter_map(|range| range.try_with_sample_rate(sample_rate))
+                    .collect::<Vec<_>>();
+                // Prefer configurations resembling the default, which the device handles best.
+                candidates.sort_by_key(|config| {
+                    (
+                        config.sample_format() != default_config.sample_format(),
+                        config.channels() != default_config.channels(),
+                    )
+                });
+                candidates.into_iter().next().ok_or(anyhow!(
+                    "Output device does not support a sample rate of {sample_rate} Hz"
+                ))?
             }
-        }
-    }
-
-    fn run_synth<T: Sample + SizedSample + FromSample<f32>>(
-        &mut self,
-        midi_msgs: Arc<SegQueue<SynthMsg>>,
-        device: Device,
-        config: StreamConfig,
-    ) -> anyhow::Result<()> {
-        Self::warm_up(midi_msgs.clone());
-        let mut done = false;
-        while !done {
-            let stream = self.get_stream::<T>(&config, &device)?;
-            stream.play()?;
-            if self.handle_messages(midi_msgs.clone()) == RelayedMessage::SystemReset {
-                done = true;
+        };
+        let sample_format = supported.sample_format();
+        let mut config = supported.config();
+        if let Some(buffer_size) = self.buffer_size {
+            if let SupportedBufferSize::Range { min, max } = supported.buffer_size()
+                && !(*min..=*max).contains(&buffer_size)
+            {
+                bail!("Buffer size {buffer_size} is outside the supported range {min}..={max}");
             }
+            config.buffer_size = BufferSize::Fixed(buffer_size);
         }
-        Ok(())
-    }
-
-    fn warm_up(midi_msgs: Arc<SegQueue<SynthMsg>>) {
-        for _ in 0..N {
-            midi_msgs.push(Self::warm_up_msg(ChannelVoiceMsg::NoteOn {
-                note: 0,
-                velocity: 0,
-            }));
-            midi_msgs.push(Self::warm_up_msg(ChannelVoiceMsg::NoteOff {
-                note: 0,
-                velocity: 0,
-            }));
-        }
+        Ok(ResolvedOutput {
+            device,
+            sample_format,
+            config,
+        })
     }
+}
 
-    fn warm_up_msg(msg: ChannelVoiceMsg) -> SynthMsg {
-        SynthMsg {
-            msg: MidiMsg::ChannelVoice {
-                channel: Channel::Ch1,
-                msg,
-            },
-            speaker: Speaker::Both,
-        }
-    }
+/// The settings an `OutputConfig` selects once the device's defaults are filled in.
+pub(crate) struct ResolvedOutput {
+    pub(crate) device: Device,
+    pub(crate) sample_format: SampleFormat,
+    pub(crate) config: StreamConfig,
+}
 
-    fn handle_messages(&mut self, midi_msgs: Arc<SegQueue<SynthMsg>>) -> RelayedMessage {
-        loop {
-            if let Some(msg) = midi_msgs.pop() {
-                if let Some(relayed) = self.decode(msg.speaker, &msg.msg) {
-                    return relayed;
-                }
-            }
-        }
+fn default_output_device() -> anyhow::Result<Device> {
+    cpal::default_host()
+        .default_output_device()
+        .ok_or(anyhow!("failed to find a default output device"))
+}
+
+fn output_device_name(device: &Device) -> anyhow::Result<String> {
+    Ok(device.description()?.name().to_owned())
+}
+
+/// Returns all available audio output devices, paired with their names.
+pub fn output_devices() -> anyhow::Result<Vec<(String, Device)>> {
+    let mut devices = vec![];
+    for device in cpal::default_host().output_devices()? {
+        devices.push((output_device_name(&device)?, device));
     }
+    Ok(devices)
+}
 
-    fn get_stream<T: Sample + SizedSample + FromSample<f32>>(
-        &self,
-        config: &StreamConfig,
-        device: &Device,
-    ) -> anyhow::Result<Stream> {
-        let sample_rate = config.sample_rate as f64;
-        let mut sound = self.sound();
-        sound.reset();
-        sound.set_sample_rate(sample_rate);
-        let mut next_value = move || sound.get_stereo();
-        let channels = config.channels as usize;
-        let err_fn = |err| eprintln!("Error on stream: {err}");
-        device
-            .build_output_stream(
-                &config,
-                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-                    write_data(data, channels, &mut next_value)
-                },
-                err_fn,
-                None,
-            )
-            .or_else(|err| bail!("{err:?}"))
+/// Returns a handle to the default audio output device.
+pub fn get_default_output_device() -> anyhow::Result<Device> {
+    let device = default_output_device()?;
+    println!("Chose audio output device {}", output_device_name(&device)?);
+    Ok(device)
+}
+
+/// Allows selecting an audio output device via the console from a complete list of output devices.
+/// The basic concept can be a model of how to do this in a GUI setting.
+pub fn choose_output_device() -> anyhow::Result<Device> {
+    let mut choices = output_devices()?;
+    match choices.len() {
+        0 => bail!("No audio output devices available"),
+        1 => {
+            let (name, device) = choices.remove(0);
+            println!("Chose audio output device {name}");
+            Ok(device)
+        }
+        _ => {
+            let c = console_choice_from("Select Audio Output Device", &choices, |choice| {
+                choice.0.as_str()
+            });
+            Ok(choices.swap_remove(c).1)
+        }
     }
 }
 
@@ -437,175 +993,44 @@ pub fn choose_midi_device(midi_in: &mut MidiInput) -> anyhow::Result<MidiInputPo
     }
 }
 
-fn write_data<T: Sample + FromSample<f32>>(
-    output: &mut [T],
-    channels: usize,
-    next_sample: &mut dyn FnMut() -> (f32, f32),
-) {
-    for frame in output.chunks_mut(channels) {
-        let sample = next_sample();
-        let left: T = Sample::from_sample::<f32>(sample.0);
-        let right: T = Sample::from_sample::<f32>(sample.1);
-
-        for (channel, sample) in frame.iter_mut().enumerate() {
-            *sample = if channel & 1 == 0 { left } else { right };
-        }
-    }
-}
-
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
-enum RelayedMessage {
-    SynthChange,
-    SystemReset,
-}
-
-#[derive(Clone)]
-struct MonoPlayer<const N: usize> {
-    states: [SharedMidiState; N],
-    next: ModNumC<usize, N>,
-    pitch2state: [Option<usize>; NUM_MIDI_VALUES],
-    recent_pitches: [Option<u8>; N],
-    synth_func: SynthFunc,
-    master_volume: Shared,
-    program_table: Arc<Mutex<ProgramTable>>,
-}
-
-impl<const N: usize> MonoPlayer<N> {
-    fn new(program_table: Arc<Mutex<ProgramTable>>) -> Self {
-        let synth_func = {
-            let program_table = program_table.lock().unwrap();
-            program_table[0].1.clone()
-        };
-        Self {
-            states: [(); N].map(|_| SharedMidiState::default()),
-            next: ModNumC::new(0),
-            pitch2state: [None; NUM_MIDI_VALUES],
-            recent_pitches: [None; N],
-            synth_func,
-            master_volume: shared(1.0),
-            program_table,
-        }
-    }
-
-    fn set_midi_to_hz(&mut self, midi_to_hz: fn(f32) -> f32) {
-        for i in 0..self.states.len() {
-            self.states[i].set_midi_to_hz(midi_to_hz);
-        }
-    }
-
-    fn sound(&self) -> Net {
-        let mut sound = Net::wrap(self.sound_at(0));
-        for i in 1..N {
-            sound = Net::binary(sound, Net::wrap(self.sound_at(i)), FrameAdd::new());
-        }
-        Net::binary(
-            sound,
-            Net::wrap(Box::new(var(&self.master_volume))),
-            FrameMul::new(),
-        )
-    }
-
-    fn decode(&mut self, msg: &MidiMsg) -> Option<RelayedMessage> {
-        match msg {
-            MidiMsg::ChannelVoice { channel: _, msg } => match msg {
-                ChannelVoiceMsg::NoteOn { note, velocity } => {
-                    if *velocity == 0_u8 {
-                        self.off(*note);
-                    } else {
-                        self.on(*note, *velocity);
-                    }
-                }
-                ChannelVoiceMsg::NoteOff { note, velocity: _ } => {
-                    self.off(*note);
-                }
-                ChannelVoiceMsg::PitchBend { bend } => {
-                    self.bend(*bend);
-                }
-                ChannelVoiceMsg::ProgramChange { program } => {
-                    let new_synth = {
-                        let program_table = self.program_table.lock().unwrap();
-                        program_table[*program as usize].1.clone()
-                    };
-                    self.change_synth(new_synth);
-                    return Some(RelayedMessage::SynthChange);
-                }
-                _ => {}
-            },
-            MidiMsg::ChannelMode { channel: _, msg } => match msg {
-                ChannelModeMsg::AllNotesOff => self.release_all(),
-                ChannelModeMsg::AllSoundOff => self.all_sounds_off(),
-                _ => {}
-            },
-            MidiMsg::SystemRealTime { msg } => match msg {
-                SystemRealTimeMsg::SystemReset => return Some(RelayedMessage::SystemReset),
-                _ => {}
-            },
-            _ => {}
-        }
-        None
-    }
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-    fn find_next_state(&mut self) -> usize {
-        for i in self.next.iter() {
-            if self.recent_pitches[i.a()].is_none() {
-                return self.claim_state(i);
-            }
-        }
-        self.pitch2state[self.recent_pitches[self.next.a()].unwrap() as usize] = None;
-        self.release(self.next.a());
-        self.claim_state(self.next)
-    }
+    use midi_msg::{MidiMsg, SystemRealTimeMsg};
 
-    fn claim_state(&mut self, state: ModNumC<usize, N>) -> usize {
-        let next = state.a();
-        self.next = state + 1;
-        next
-    }
+    use crate::{
+        io::{MsgQueue, SynthBuilder},
+        sinks::{BufferSink, NullSink},
+        sound_builders::SharedProgramTable,
+        sounds::options,
+    };
 
-    fn on(&mut self, pitch: u8, velocity: u8) {
-        self.master_volume.set_value(1.0);
-        let selected = self.find_next_state();
-        self.states[selected].on(pitch, velocity);
-        self.pitch2state[pitch as usize] = Some(selected);
-        self.recent_pitches[selected] = Some(pitch);
-    }
-
-    fn off(&mut self, pitch: u8) {
-        if let Some(i) = self.pitch2state[pitch as usize] {
-            if self.recent_pitches[i] == Some(pitch) {
-                self.release(i);
-            }
-            self.pitch2state[pitch as usize] = None;
-        }
-    }
-
-    fn change_synth(&mut self, new_synth: SynthFunc) {
-        self.all_sounds_off();
-        self.synth_func = new_synth;
-    }
-
-    fn bend(&mut self, bend: u16) {
-        for state in self.states.iter_mut() {
-            state.bend(bend);
-        }
-    }
-
-    fn sound_at(&self, i: usize) -> Box<dyn AudioUnit> {
-        (self.synth_func)(&self.states[i])
-    }
-
-    fn release(&mut self, i: usize) {
-        self.recent_pitches[i] = None;
-        self.states[i].off();
-    }
-
-    fn release_all(&mut self) {
-        for i in 0..N {
-            self.release(i);
-        }
+    #[test]
+    fn test_synth_stop_join() {
+        let midi_msgs = Arc::new(MsgQueue::new());
+        let synth = SynthBuilder::<4>::new(Arc::new(SharedProgramTable::new(options())))
+            .with_midi_messages(midi_msgs.clone())
+            .with_sink(BufferSink::new(44100.0))
+            .start()
+            .unwrap();
+        synth.stop();
+        synth.join();
+        assert_eq!(Arc::strong_count(&midi_msgs), 1);
     }
 
-    fn all_sounds_off(&mut self) {
-        self.master_volume.set_value(0.0);
+    #[test]
+    fn test_system_reset_ends_relay() {
+        let midi_msgs = Arc::new(MsgQueue::new());
+        let synth = SynthBuilder::<4>::new(Arc::new(SharedProgramTable::new(options())))
+            .with_midi_messages(midi_msgs.clone())
+            .with_sink(NullSink::new(44100.0))
+            .start()
+            .unwrap();
+        midi_msgs.push(MidiMsg::SystemRealTime {
+            msg: SystemRealTimeMsg::SystemReset,
+        });
+        synth.join();
+        assert_eq!(Arc::strong_count(&midi_msgs), 1);
     }
 }
@@ -8,8 +8,23 @@
 //!   converted into `SharedMidiState` objects that translate the sounds represented by those messages into
 //!   [fundsp `Shared` atomic variables](https://docs.rs/fundsp/0.10.0/fundsp/audionode/struct.Shared.html).
 //!   * `SynthFunc` functions translate `SharedMidiState` objects into specific [fundsp](https://crates.io/crates/fundsp) audio graphs.
+//! * The `arpeggiator` module contains `Arpeggiator`, which plays the notes of held chords one at a time,
+//!   following an internal tempo or incoming MIDI clock.
+//! * The `clock` module contains `MidiClock`, which follows the tempo and transport of an external MIDI clock,
+//!   and `start_clock_thread()`, which sends MIDI clock as a master.
 //! * The `io` module contains functions and data types for obtaining messages from MIDI devices and playing  
 //!   [fundsp](https://crates.io/crates/fundsp) audio graphs through the computer's speakers.
+//! * The `looper` module contains `Looper`, which records incoming notes and plays them back in a cycle,
+//!   with overdubbed layers that can be undone.
+//! * The `sequencer` module contains `Sequencer`, which plays repeating patterns of notes into a synthesizer
+//!   on several tracks, without a MIDI controller.
+//! * The `sinks` module contains the `AudioSink` trait, which abstracts where the audio goes, along with
+//!   implementations for audio output devices, for discarding audio, and for capturing audio in buffers or files.
+//! * The `synth` module contains `Synth`, a synthesizer that renders into caller-provided buffers without
+//!   spawning threads or opening devices, along with the `Renderer` used by audio sinks.
+//! * The `velocity` module contains `VelocityCurve`, which converts `Note On` velocities into volumes.
+//! * The `zones` module contains `Zone`, which assigns a range of keys and velocities to a sound, so that
+//!   synthesizers can split the keyboard among sounds or layer them.
 //! * The `sound_builders` module contains functions that wrap [fundsp](https://crates.io/crates/fundsp) audio graphs
 //!   into `SynthFunc` functions with a variety of properties.
 //! * The `sounds` module contains `SynthFunc` functions that produce a variety of live sounds.
@@ -19,16 +34,24 @@
 //! * [`basic_demo.rs`](https://github.com/gjf2a/midi_fundsp/blob/master/examples/basic_demo.rs) opens the first MIDI
 //! device it finds and plays a simple triangle waveform sound in response to MIDI events.
 //! * [`stereo_demo.rs`](https://github.com/gjf2a/midi_fundsp/blob/master/examples/stereo_demo.rs) also opens the first MIDI
-//! device it finds. It plays notes below middle C through the left speaker using a Moog Pulse sound, and notes
-//! at Middle C or higher through the right speaker using a Moog Triangle sound.
+//! device it finds. It uses zones to play notes below middle C through the left speaker using a Moog Pulse sound,
+//! and notes at Middle C or higher through the right speaker using a Pulse sound.
 //! * [`choice_demo.rs`](https://github.com/gjf2a/midi_fundsp/blob/master/examples/choice_demo.rs) allows the user to choose
 //! one from among all connected MIDI devices. The user can then choose any sound from the `sounds` module for the program's
 //! response to MIDI events.
 
+pub mod arpeggiator;
+pub mod clock;
 pub mod io;
+pub mod looper;
+pub mod sequencer;
+pub mod sinks;
 pub mod sound_builders;
 pub mod sounds;
+pub mod synth;
 pub mod tunings;
+pub mod velocity;
+pub mod zones;
 
 use std::fmt::Debug;
 use std::sync::Arc;
@@ -40,6 +63,7 @@ use fundsp::prelude::{An, AudioUnit, FrameMul};
 use fundsp::prelude64::{shared, var};
 use fundsp::shared::{Shared, Var};
 use midi_msg::MidiMsg;
+use tunings::Tuning;
 
 /// MIDI values for pitch and velocity range from 0 to 127.
 pub const MAX_MIDI_VALUE: u8 = 127;
@@ -67,7 +91,7 @@ pub struct SharedMidiState {
     velocity: Shared,
     control: Shared,
     pitch_bend: Shared,
-    midi_to_hz: fn(f32) -> f32,
+    tuning: Arc<dyn Tuning>,
 }
 
 impl Default for SharedMidiState {
@@ -77,7 +101,7 @@ impl Default for SharedMidiState {
             velocity: Default::default(),
             control: shared(CONTROL_OFF),
             pitch_bend: shared(1.0),
-            midi_to_hz: midi_hz,
+            tuning: Arc::new(midi_hz::<f32>),
         }
     }
 }
@@ -96,7 +120,13 @@ impl Debug for SharedMidiState {
 impl SharedMidiState {
     /// Changes how MIDI notes are converted to pitches. Defaults to equal temperament.
     pub fn set_midi_to_hz(&mut self, midi_to_hz: fn(f32) -> f32) {
-        self.midi_to_hz = midi_to_hz;
+        self.set_tuning(Arc::new(midi_to_hz));
+    }
+
+    /// Changes how MIDI notes are converted to pitches to `tuning`, which, unlike a function passed to
+    /// `set_midi_to_hz()`, may carry data such as a scale loaded from a file.
+    pub fn set_tuning(&mut self, tuning: Arc<dyn Tuning>) {
+        self.tuning = tuning;
     }
 
     /// Returns the most recent `Note On` pitch, modified by the most recent `Pitch Bend` event.
@@ -109,6 +139,11 @@ impl SharedMidiState {
         var(&self.control)
     }
 
+    /// Returns the volume of the most recent `Note On`, from `0.0` to `1.0`, as given by its velocity.
+    pub fn velocity_var(&self) -> An<Var> {
+        var(&self.velocity)
+    }
+
     /// Returns the current volume.
     ///
     /// The volume is determined from the velocity of the most recent `Note On` event in combination with the
@@ -152,12 +187,31 @@ impl SharedMidiState {
 
     /// Encodes a MIDI `Note On` event.
     pub fn on(&self, pitch: u8, velocity: u8) {
-        self.pitch.set_value((self.midi_to_hz)(pitch as f32));
-        self.velocity
-            .set_value(velocity as f32 / MAX_MIDI_VALUE as f32);
+        self.on_at_volume(pitch, velocity as f32 / MAX_MIDI_VALUE as f32);
+    }
+
+    /// Encodes a MIDI `Note On` event whose velocity a `VelocityCurve` has converted to `volume`.
+    pub(crate) fn on_at_volume(&self, pitch: u8, volume: f32) {
+        self.pitch.set_value(self.tuning.midi_to_hz(pitch as f32));
+        self.velocity.set_value(volume);
         self.control.set_value(CONTROL_ON);
     }
 
+    /// Retunes the sounding note to the current frequency of `pitch` in its tuning, without restarting it.
+    pub fn retune(&self, pitch: u8) {
+        self.pitch.set_value(self.tuning.midi_to_hz(pitch as f32));
+    }
+
+    /// Returns the frequency in Hz of the most recent `Note On` pitch, before any `Pitch Bend`.
+    pub(crate) fn hz(&self) -> f32 {
+        self.pitch.value()
+    }
+
+    /// Sounds the current note at `hz`, regardless of its tuning, without restarting it.
+    pub(crate) fn set_hz(&self, hz: f32) {
+        self.pitch.set_value(hz);
+    }
+
     /// Encodes a MIDI `Note Off` event.
     pub fn off(&self) {
         self.control.set_value(CONTROL_OFF);
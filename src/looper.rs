@@ -0,0 +1,598 @@
+//! A MIDI looper, which records incoming notes and plays them back in a repeating cycle.
+//!
+//! The first recording sets the length of the loop, optionally rounded to a multiple of a chosen duration
+//! such as one bar. Later recordings overdub new layers on top of it, and `undo()` removes the most recent
+//! layer. Played-back notes may go to a different speaker, or on a different channel to reach a zone with a
+//! different program, than the live playing they were recorded from.
+//!
+//! A `Looper` sits between a source of messages, such as an input thread, and a synthesizer. Every message
+//! passes through to the synthesizer as it arrives, and channel messages are also recorded.
+
+use std::{
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam_utils::atomic::AtomicCell;
+use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg};
+
+use crate::io::{MsgQueue, Speaker, SynthMsg};
+
+/// A recorded message and its offset from the start of the loop.
+type Event = (Duration, SynthMsg);
+
+#[derive(Clone, Debug, Default)]
+struct Layer {
+    /// Events in order of offset.
+    events: Vec<Event>,
+    /// Index of the next event to play in the current cycle.
+    cursor: usize,
+    /// Notes the layer is playing, so that they can be released if it is removed.
+    sounding: Vec<SynthMsg>,
+}
+
+/// Records and plays back layers of MIDI messages in a cycle.
+#[derive(Clone, Debug, Default)]
+pub struct Looper {
+    layers: Vec<Layer>,
+    /// Length of the loop, once the first layer has been recorded.
+    length: Option<Duration>,
+    /// Start of the current cycle, or of the first recording while its length is unknown.
+    cycle_start: Option<Instant>,
+    /// Events recorded since recording began or the cycle last restarted.
+    recording: Option<Vec<Event>>,
+    /// Notes held during recording, released at the end of the layer if still held when recording stops.
+    held: Vec<SynthMsg>,
+    quantum: Option<Duration>,
+    speaker: Option<Speaker>,
+    channel: Option<Channel>,
+}
+
+impl Looper {
+    /// Creates an empty looper whose loop is exactly as long as its first recording.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rounds the length of the loop to the nearest multiple of `quantum`, such as the duration of a bar.
+    pub fn with_quantum(mut self, quantum: Duration) -> Self {
+        self.quantum = (!quantum.is_zero()).then_some(quantum);
+        self
+    }
+
+    /// Plays back recorded messages through `speaker`, rather than the speaker they were played for.
+    pub fn with_speaker(mut self, speaker: Speaker) -> Self {
+        self.speaker = Some(speaker);
+        self
+    }
+
+    /// Plays back recorded messages on `channel`, rather than the channel they were played on.
+    /// Zones with a channel of their own can thereby play the loop with a different program.
+    pub fn with_channel(mut self, channel: Channel) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Returns the length of the loop, or `None` if the first layer has yet to be recorded.
+    pub fn length(&self) -> Option<Duration> {
+        self.length
+    }
+
+    /// Returns the number of recorded layers.
+    pub fn layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns `true` while recording the first layer or overdubbing another.
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Starts or stops recording at `time`, adding to `out` any messages to send in response.
+    ///
+    /// The first recording to stop sets the length of the loop, which then plays back from the point
+    /// the recording reached.
+    /// Each later recording overdubs a layer that plays back from the start of the following cycle.
+    pub fn record(&mut self, time: Instant, out: &mut Vec<SynthMsg>) {
+        self.tick(time, out);
+        match (self.recording.is_some(), self.length) {
+            (false, None) => {
+                self.cycle_start = Some(time);
+                self.recording = Some(vec![]);
+            }
+            (false, Some(_)) => {
+                self.layers.push(Layer::default());
+                self.recording = Some(vec![]);
+            }
+            (true, None) => {
+                // Playback begins one loop length after recording began, skipping anything already missed.
+                let start = self.cycle_start.unwrap_or(time);
+                let length = self.quantize(time - start);
+                self.length = Some(length);
+                self.cycle_start = Some(start + length);
+                self.layers.push(Layer::default());
+                self.stop_recording(time);
+            }
+            (true, Some(_)) => self.stop_recording(time),
+        }
+    }
+
+    /// Removes the most recently recorded layer at `time`, releasing its notes. If none remain, the loop
+    /// is cleared, so that the next recording sets a new length.
+    pub fn undo(&mut self, time: Instant, out: &mut Vec<SynthMsg>) {
+        self.tick(time, out);
+        self.recording = None;
+        self.held.clear();
+        if let Some(layer) = self.layers.pop() {
+            self.release(layer, time, out);
+        }
+        if self.layers.is_empty() {
+            self.clear(time, out);
+        }
+    }
+
+    /// Removes every layer at `time`, releasing their notes, so that the next recording sets a new length.
+    pub fn clear(&mut self, time: Instant, out: &mut Vec<SynthMsg>) {
+        for layer in std::mem::take(&mut self.layers) {
+            self.release(layer, time, out);
+        }
+        self.length = None;
+        self.cycle_start = None;
+        self.recording = None;
+        self.held.clear();
+    }
+
+    /// Handles `msg`, received at `time`, by recording it if recording and adding it to `out`.
+    /// Call `tick()` with `time` first, so that the messages in `out` remain in order.
+    pub fn handle(&mut self, msg: SynthMsg, time: Instant, out: &mut Vec<SynthMsg>) {
+        if let (Some(recording), Some(start)) = (self.recording.as_mut(), self.cycle_start)
+            && matches!(msg.msg, MidiMsg::ChannelVoice { .. })
+        {
+            match note_of(&msg) {
+                Some((_, true)) => self.held.push(msg.clone()),
+                Some((note, false)) => self.held.retain(|held| !same_note(held, &msg, note)),
+                None => {}
+            }
+            let offset = time.saturating_duration_since(start);
+            recording.push((offset, msg.clone()));
+        }
+        out.push(msg);
+    }
+
+    /// Returns the next moment at which `tick()` has something to do, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let (length, start) = (self.length?, self.cycle_start?);
+        self.layers
+            .iter()
+            .filter_map(|layer| layer.events.get(layer.cursor))
+            .map(|(offset, _)| start + *offset)
+            .chain(std::iter::once(start + length))
+            .min()
+    }
+
+    /// Adds to `out`, in order of time, every recorded message due at or before `now`.
+    pub fn tick(&mut self, now: Instant, out: &mut Vec<SynthMsg>) {
+        while let Some(deadline) = self.next_deadline()
+            && deadline <= now
+        {
+            let (Some(length), Some(start)) = (self.length, self.cycle_start) else {
+                return;
+            };
+            if let Some(layer) = self.layers.iter_mut().find(|layer| {
+                layer
+                    .events
+                    .get(layer.cursor)
+                    .map(|(offset, _)| start + *offset)
+                    == Some(deadline)
+            }) {
+                let msg = playback(
+                    &layer.events[layer.cursor].1,
+                    self.speaker,
+                    self.channel,
+                    deadline,
+                );
+                layer.cursor += 1;
+                match note_of(&msg) {
+                    Some((_, true)) => layer.sounding.push(msg.clone()),
+                    Some((note, false)) => {
+                        layer.sounding.retain(|held| !same_note(held, &msg, note))
+                    }
+                    None => {}
+                }
+                out.push(msg);
+            } else {
+                self.next_cycle(start + length, length);
+            }
+        }
+    }
+
+    /// Starts the next cycle at `time`, adding to the layer being overdubbed whatever was recorded in the last.
+    fn next_cycle(&mut self, time: Instant, length: Duration) {
+        if let Some(recording) = self.recording.as_mut() {
+            let events = std::mem::take(recording);
+            if let Some(layer) = self.layers.last_mut() {
+                add_events(layer, events, length, None);
+            }
+        }
+        for layer in self.layers.iter_mut() {
+            layer.cursor = 0;
+        }
+        self.cycle_start = Some(time);
+    }
+
+    /// Adds what was recorded to the last layer at `time`. Its events already due in the current cycle
+    /// are left for the next.
+    fn stop_recording(&mut self, time: Instant) {
+        let (Some(mut events), Some(length), Some(start)) =
+            (self.recording.take(), self.length, self.cycle_start)
+        else {
+            return;
+        };
+        // Notes still held are released as the loop restarts.
+        for held in self.held.drain(..) {
+            if let Some((note, _)) = note_of(&held)
+                && let MidiMsg::ChannelVoice { channel, .. } = held.msg
+            {
+                let off = MidiMsg::ChannelVoice {
+                    channel,
+                    msg: ChannelVoiceMsg::NoteOff { note, velocity: 0 },
+                };
+                events.insert(0, (Duration::ZERO, SynthMsg { msg: off, ..held }));
+            }
+        }
+        let played = time.checked_duration_since(start);
+        if let Some(layer) = self.layers.last_mut() {
+            add_events(layer, events, length, played);
+        }
+    }
+
+    fn release(&self, layer: Layer, time: Instant, out: &mut Vec<SynthMsg>) {
+        for msg in layer.sounding {
+            if let Some((note, _)) = note_of(&msg)
+                && let MidiMsg::ChannelVoice { channel, .. } = msg.msg
+            {
+                out.push(SynthMsg {
+                    msg: MidiMsg::ChannelVoice {
+                        channel,
+                        msg: ChannelVoiceMsg::NoteOff { note, velocity: 0 },
+                    },
+                    speaker: msg.speaker,
+                    time: Some(time),
+                });
+            }
+        }
+    }
+
+    fn quantize(&self, length: Duration) -> Duration {
+        match self.quantum {
+            None => length.max(Duration::from_millis(1)),
+            Some(quantum) => {
+                let multiple = (length.as_secs_f64() / quantum.as_secs_f64())
+                    .round()
+                    .max(1.0);
+                quantum.mul_f64(multiple)
+            }
+        }
+    }
+
+    /// Starts playing on a thread of its own, passing messages from `incoming` to `outgoing` and adding
+    /// the loop's messages to them.
+    pub fn start(
+        mut self,
+        incoming: Arc<MsgQueue<SynthMsg>>,
+        outgoing: Arc<MsgQueue<SynthMsg>>,
+    ) -> LooperHandle {
+        let commands = Arc::new(MsgQueue::new());
+        let stop = Arc::new(AtomicCell::new(false));
+        let (thread_incoming, thread_commands, thread_stop) =
+            (incoming.clone(), commands.clone(), stop.clone());
+        let thread = std::thread::spawn(move || {
+            let (incoming, commands, stop) = (thread_incoming, thread_commands, thread_stop);
+            let mut out = vec![];
+            while !stop.load() {
+                // Commands arrive on their own queue, so waiting for incoming messages also ends
+                // once one is pending. `LooperHandle` wakes `incoming` after pushing it.
+                let msg = incoming
+                    .pop_wait_or(|| stop.load() || !commands.is_empty(), self.next_deadline());
+                let now = Instant::now();
+                while let Some(command) = commands.pop() {
+                    match command {
+                        LooperCommand::Record => self.record(now, &mut out),
+                        LooperCommand::Undo => self.undo(now, &mut out),
+                        LooperCommand::Clear => self.clear(now, &mut out),
+                    }
+                }
+                if let Some(msg) = msg {
+                    let time = msg.time.unwrap_or(now);
+                    self.tick(time, &mut out);
+                    self.handle(msg, time, &mut out);
+                }
+                self.tick(now, &mut out);
+                for msg in out.drain(..) {
+                    outgoing.push(msg);
+                }
+            }
+            self.clear(Instant::now(), &mut out);
+            for msg in out.drain(..) {
+                outgoing.push(msg);
+            }
+        });
+        LooperHandle {
+            incoming,
+            commands,
+            stop,
+            thread,
+        }
+    }
+}
+
+/// Returns the pitch of `msg` if it is a `Note On` or `Note Off`, along with `true` for a `Note On`.
+fn note_of(msg: &SynthMsg) -> Option<(u8, bool)> {
+    match msg.msg {
+        MidiMsg::ChannelVoice {
+            msg: ChannelVoiceMsg::NoteOn { note, velocity },
+            ..
+        } => Some((note, velocity > 0)),
+        MidiMsg::ChannelVoice {
+            msg: ChannelVoiceMsg::NoteOff { note, .. },
+            ..
+        } => Some((note, false)),
+        _ => None,
+    }
+}
+
+/// Returns `true` if `held` is a `Note On` for `note` on the channel and speaker of `msg`.
+fn same_note(held: &SynthMsg, msg: &SynthMsg, note: u8) -> bool {
+    let channel = |msg: &SynthMsg| match msg.msg {
+        MidiMsg::ChannelVoice { channel, .. } => Some(channel),
+        _ => None,
+    };
+    note_of(held) == Some((note, true))
+        && channel(held) == channel(msg)
+        && held.speaker == msg.speaker
+}
+
+/// Merges `events` into `layer`, wrapping offsets beyond `length` back into the loop. If `played` is given,
+/// the layer's next event in the current cycle is the first whose offset is beyond it.
+fn add_events(layer: &mut Layer, events: Vec<Event>, length: Duration, played: Option<Duration>) {
+    layer.events.extend(events.into_iter().map(|(offset, msg)| {
+        let wrapped = Duration::from_secs_f64(offset.as_secs_f64() % length.as_secs_f64());
+        (wrapped, msg)
+    }));
+    layer.events.sort_by_key(|(offset, _)| *offset);
+    if let Some(played) = played {
+        layer.cursor = layer
+            .events
+            .partition_point(|(offset, _)| *offset <= played);
+    }
+}
+
+/// Returns `msg` as played back at `time`, through `speaker` and on `channel` if given.
+fn playback(
+    msg: &SynthMsg,
+    speaker: Option<Speaker>,
+    channel: Option<Channel>,
+    time: Instant,
+) -> SynthMsg {
+    let msg_out = match (&msg.msg, channel) {
+        (MidiMsg::ChannelVoice { msg, .. }, Some(channel)) => {
+            MidiMsg::ChannelVoice { channel, msg: *msg }
+        }
+        (msg, _) => msg.clone(),
+    };
+    SynthMsg {
+        msg: msg_out,
+        speaker: speaker.unwrap_or(msg.speaker),
+        time: Some(time),
+    }
+}
+
+enum LooperCommand {
+    Record,
+    Undo,
+    Clear,
+}
+
+/// A looper started by `Looper::start()`.
+///
+/// Dropping a `LooperHandle` leaves the looper playing.
+pub struct LooperHandle {
+    incoming: Arc<MsgQueue<SynthMsg>>,
+    commands: Arc<MsgQueue<LooperCommand>>,
+    stop: Arc<AtomicCell<bool>>,
+    thread: JoinHandle<()>,
+}
+
+impl LooperHandle {
+    /// Starts or stops recording, as described for `Looper::record()`.
+    pub fn record(&self) {
+        self.command(LooperCommand::Record);
+    }
+
+    /// Removes the most recently recorded layer, as described for `Looper::undo()`.
+    pub fn undo(&self) {
+        self.command(LooperCommand::Undo);
+    }
+
+    /// Removes every layer, as described for `Looper::clear()`.
+    pub fn clear(&self) {
+        self.command(LooperCommand::Clear);
+    }
+
+    fn command(&self, command: LooperCommand) {
+        self.commands.push(command);
+        self.incoming.wake();
+    }
+
+    /// Asks the looper to release its sounding notes and stop. Messages still in its incoming queue
+    /// remain there.
+    pub fn stop(&self) {
+        self.stop.store(true);
+        self.incoming.wake();
+    }
+
+    /// Waits for the looper's thread to exit after `stop()`.
+    pub fn join(self) {
+        if let Err(panic) = self.thread.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg};
+
+    use crate::{
+        io::{MsgQueue, Speaker, SynthMsg},
+        looper::Looper,
+    };
+
+    fn note(note: u8, on: bool) -> SynthMsg {
+        let msg = if on {
+            ChannelVoiceMsg::NoteOn {
+                note,
+                velocity: 100,
+            }
+        } else {
+            ChannelVoiceMsg::NoteOff { note, velocity: 0 }
+        };
+        SynthMsg::new(
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg,
+            },
+            Speaker::Both,
+        )
+    }
+
+    /// Returns each note in `out` played back through the right speaker, with its time in milliseconds
+    /// after `start` and whether it is a `Note On`.
+    fn played(out: &[SynthMsg], start: Instant) -> Vec<(u128, u8, bool)> {
+        out.iter()
+            .filter(|msg| msg.speaker == Speaker::Right)
+            .map(|msg| match msg.msg {
+                MidiMsg::ChannelVoice {
+                    channel: Channel::Ch2,
+                    msg: ChannelVoiceMsg::NoteOn { note, .. },
+                } => ((msg.time.unwrap() - start).as_millis(), note, true),
+                MidiMsg::ChannelVoice {
+                    channel: Channel::Ch2,
+                    msg: ChannelVoiceMsg::NoteOff { note, .. },
+                } => ((msg.time.unwrap() - start).as_millis(), note, false),
+                _ => panic!("unexpected message {:?}", msg.msg),
+            })
+            .collect()
+    }
+
+    fn play(looper: &mut Looper, msg: SynthMsg, time: Instant, out: &mut Vec<SynthMsg>) {
+        looper.tick(time, out);
+        looper.handle(msg, time, out);
+    }
+
+    #[test]
+    fn test_looper_overdub_and_undo() {
+        let mut looper = Looper::new()
+            .with_quantum(Duration::from_millis(500))
+            .with_speaker(Speaker::Right)
+            .with_channel(Channel::Ch2);
+        let start = Instant::now();
+        let ms = |ms: u64| start + Duration::from_millis(ms);
+        let mut out = vec![];
+
+        // Record a 1000 ms loop, stopping a little late with a note still held, which is released as the
+        // loop restarts.
+        looper.record(ms(0), &mut out);
+        play(&mut looper, note(60, true), ms(100), &mut out);
+        play(&mut looper, note(60, false), ms(300), &mut out);
+        play(&mut looper, note(64, true), ms(900), &mut out);
+        looper.record(ms(1050), &mut out);
+        assert_eq!(looper.length(), Some(Duration::from_millis(1000)));
+        assert_eq!(out.len(), 3);
+        out.clear();
+        play(&mut looper, note(64, false), ms(1100), &mut out);
+        looper.tick(ms(1999), &mut out);
+        assert_eq!(
+            played(&out, start),
+            vec![(1100, 60, true), (1300, 60, false), (1900, 64, true)]
+        );
+
+        // Overdub a second layer, heard from the following cycle.
+        out.clear();
+        looper.record(ms(2000), &mut out);
+        play(&mut looper, note(67, true), ms(2200), &mut out);
+        play(&mut looper, note(67, false), ms(2400), &mut out);
+        looper.record(ms(2500), &mut out);
+        out.retain(|msg| msg.speaker == Speaker::Right);
+        looper.tick(ms(3999), &mut out);
+        assert_eq!(looper.layers(), 2);
+        assert_eq!(
+            played(&out, start),
+            vec![
+                (2000, 64, false),
+                (2100, 60, true),
+                (2300, 60, false),
+                (2900, 64, true),
+                (3000, 64, false),
+                (3100, 60, true),
+                (3200, 67, true),
+                (3300, 60, false),
+                (3400, 67, false),
+                (3900, 64, true),
+            ]
+        );
+
+        // Undoing removes the second layer; undoing again releases the first layer's held note and clears it.
+        out.clear();
+        looper.undo(ms(4250), &mut out);
+        assert_eq!(
+            played(&out, start),
+            vec![
+                (4000, 64, false),
+                (4100, 60, true),
+                (4200, 67, true),
+                (4250, 67, false)
+            ]
+        );
+        out.clear();
+        looper.undo(ms(4250), &mut out);
+        assert_eq!(played(&out, start), vec![(4250, 60, false)]);
+        assert_eq!(looper.length(), None);
+        assert_eq!(looper.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_looper_thread_commands() {
+        let incoming = Arc::new(MsgQueue::new());
+        let outgoing = Arc::new(MsgQueue::new());
+        let looper = Looper::new()
+            .with_quantum(Duration::from_millis(10))
+            .with_speaker(Speaker::Right)
+            .with_channel(Channel::Ch2)
+            .start(incoming.clone(), outgoing.clone());
+        // Waiting for each message to pass through keeps it in order with the commands around it.
+        looper.record();
+        for on in [true, false] {
+            incoming.push(note(60, on));
+            assert_eq!(outgoing.pop_wait().speaker, Speaker::Both);
+        }
+        looper.record();
+        let played = outgoing.pop_wait();
+        assert_eq!(played.speaker, Speaker::Right);
+        assert!(matches!(
+            played.msg,
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch2,
+                msg: ChannelVoiceMsg::NoteOn { note: 60, .. }
+            }
+        ));
+        looper.stop();
+        looper.join();
+    }
+}
@@ -0,0 +1,644 @@
+//! A step sequencer, which plays patterns of notes into a synthesizer without a MIDI controller.
+//!
+//! A `Sequencer` plays one or more `Track` objects at a shared tempo. Each track repeats a `Pattern` of
+//! `Step` objects on its own MIDI channel and `Speaker`, so tracks can reach different zones or speakers.
+//! The sequencer sends its notes to the same `MsgQueue` an input thread fills, stamped with the time of
+//! their steps, so that the synthesizer renders them at the right audio frames.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use midi_fundsp::{
+//!     io::{Speaker, SynthBuilder},
+//!     sequencer::{Pattern, Sequencer, Step, Track},
+//!     sound_builders::SharedProgramTable,
+//!     sounds::options,
+//! };
+//!
+//! let synth = SynthBuilder::<10>::new(Arc::new(SharedProgramTable::new(options())))
+//!     .start()
+//!     .unwrap();
+//! let bass = Pattern::new(vec![Step::note(36).tied(), Step::note(36), Step::rest(), Step::note(43)]);
+//! let sequencer = Sequencer::new(110.0, vec![Track::new(bass).with_speaker(Speaker::Left)])
+//!     .start(synth.messages());
+//! ```
+
+use std::{
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crossbeam_utils::atomic::AtomicCell;
+use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg};
+
+use crate::{
+    clock::{CLOCKS_PER_BEAT, MidiClock},
+    io::{MsgQueue, Speaker, SynthMsg},
+};
+
+/// One step of a `Pattern`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Step {
+    /// MIDI pitch to play, or `None` for a rest.
+    pub note: Option<u8>,
+    /// `Note On` velocity.
+    pub velocity: u8,
+    /// Fraction of the step, from `0.0` to `1.0`, for which the note sounds.
+    pub gate: f32,
+    /// If `true`, the note sounds until the next step. If that step plays the same pitch, the note
+    /// continues through it without being struck again.
+    pub tie: bool,
+}
+
+impl Step {
+    /// Creates a step playing `note` at velocity 100 for half of the step.
+    pub fn note(note: u8) -> Self {
+        Self {
+            note: Some(note),
+            velocity: 100,
+            gate: 0.5,
+            tie: false,
+        }
+    }
+
+    /// Creates a step that plays nothing.
+    pub fn rest() -> Self {
+        Self {
+            note: None,
+            ..Self::note(0)
+        }
+    }
+
+    /// Plays the note at `velocity`.
+    pub fn with_velocity(mut self, velocity: u8) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
+    /// Sounds the note for the fraction `gate` of the step.
+    pub fn with_gate(mut self, gate: f32) -> Self {
+        self.gate = gate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Ties the note to the next step.
+    pub fn tied(mut self) -> Self {
+        self.tie = true;
+        self
+    }
+}
+
+/// A sequence of steps that a `Track` repeats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    /// Steps in the order played.
+    pub steps: Vec<Step>,
+    /// Number of steps in each beat: `1` plays quarter notes, `2` eighth notes, and `4` sixteenth notes.
+    pub steps_per_beat: u8,
+}
+
+impl Pattern {
+    /// Creates a pattern that plays `steps` as sixteenth notes.
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self {
+            steps,
+            steps_per_beat: 4,
+        }
+    }
+
+    /// Takes `steps_per_beat` steps in each beat.
+    pub fn with_rate(mut self, steps_per_beat: u8) -> Self {
+        self.steps_per_beat = steps_per_beat.max(1);
+        self
+    }
+}
+
+/// A pattern and the channel and speaker to which its notes are sent.
+#[derive(Clone, Debug)]
+pub struct Track {
+    /// Pattern the track repeats.
+    pub pattern: Pattern,
+    /// Channel of the track's messages. Zones with a channel respond only to their own.
+    pub channel: Channel,
+    /// Speaker to which the track's messages are sent.
+    pub speaker: Speaker,
+    pending: Option<Pattern>,
+    position: usize,
+    next_step: Option<Instant>,
+    /// Pitch currently sounding, and when it is to be released, or `None` if tied to the next step.
+    sounding: Option<(u8, Option<Instant>)>,
+    /// Step of the song last taken while following a `MidiClock`, counted from its start.
+    clock_step: Option<u64>,
+}
+
+impl Track {
+    /// Creates a track that plays `pattern` on channel 1 through both speakers.
+    pub fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            channel: Channel::Ch1,
+            speaker: Speaker::Both,
+            pending: None,
+            position: 0,
+            next_step: None,
+            sounding: None,
+            clock_step: None,
+        }
+    }
+
+    /// Sends the track's messages on `channel`.
+    pub fn with_channel(mut self, channel: Channel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    /// Sends the track's messages to `speaker`.
+    pub fn with_speaker(mut self, speaker: Speaker) -> Self {
+        self.speaker = speaker;
+        self
+    }
+
+    /// Returns the number of MIDI `Timing Clock` messages in one step.
+    fn clocks_per_step(&self) -> u64 {
+        (CLOCKS_PER_BEAT / self.pattern.steps_per_beat as u32).max(1) as u64
+    }
+
+    fn release_time(&self) -> Option<Instant> {
+        self.sounding.and_then(|(_, off)| off)
+    }
+
+    fn take_step(&mut self, time: Instant, step_duration: Duration, out: &mut Vec<SynthMsg>) {
+        self.next_step = Some(time + step_duration);
+        let Some(step) = self.pattern.steps.get(self.position).copied() else {
+            self.advance();
+            return;
+        };
+        let release = (!step.tie).then(|| time + step_duration.mul_f32(step.gate));
+        match self.sounding {
+            Some((note, None)) if step.note == Some(note) => {
+                self.sounding = Some((note, release));
+            }
+            _ => {
+                self.release(time, out);
+                if let Some(note) = step.note {
+                    out.push(self.msg(
+                        ChannelVoiceMsg::NoteOn {
+                            note,
+                            velocity: step.velocity,
+                        },
+                        time,
+                    ));
+                    self.sounding = Some((note, release));
+                }
+            }
+        }
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        self.position += 1;
+        if self.position >= self.pattern.steps.len() {
+            self.position = 0;
+            if let Some(pattern) = self.pending.take() {
+                self.pattern = pattern;
+            }
+        }
+    }
+
+    fn release(&mut self, time: Instant, out: &mut Vec<SynthMsg>) {
+        if let Some((note, _)) = self.sounding.take() {
+            out.push(self.msg(ChannelVoiceMsg::NoteOff { note, velocity: 0 }, time));
+        }
+    }
+
+    fn msg(&self, msg: ChannelVoiceMsg, time: Instant) -> SynthMsg {
+        SynthMsg {
+            msg: MidiMsg::ChannelVoice {
+                channel: self.channel,
+                msg,
+            },
+            speaker: self.speaker,
+            time: Some(time),
+        }
+    }
+}
+
+/// Plays tracks of patterns at a shared tempo.
+///
+/// `start()` runs a sequencer on a thread of its own. Alternatively, its owner may call `tick()` whenever
+/// `next_deadline()` arrives, such as when rendering a `Synth` buffer.
+#[derive(Clone, Debug)]
+pub struct Sequencer {
+    tracks: Vec<Track>,
+    bpm: f32,
+    clock: Option<Arc<MidiClock>>,
+    /// Number of relocations of the clock when last ticked, so that new ones can be noticed.
+    clock_relocations: u64,
+}
+
+impl Sequencer {
+    /// Creates a sequencer playing `tracks` at `bpm` beats per minute.
+    pub fn new(bpm: f32, tracks: Vec<Track>) -> Self {
+        Self {
+            tracks,
+            bpm,
+            clock: None,
+            clock_relocations: 0,
+        }
+    }
+
+    /// Follows the tempo and transport of `clock`, such as one returned by `SynthHandle::clock()`.
+    ///
+    /// Each step is taken when the song position of the clock reaches it, so `Start` returns to the
+    /// first step, `Stop` releases sounding notes and pauses, `Continue` resumes, and a `Song Position
+    /// Pointer` moves to the step at its position. A step is taken at the first `tick()` after the clock
+    /// message that reaches it, so a sequencer running on its own thread wakes shortly after each
+    /// expected `Timing Clock` near a step, and once per `Timing Clock` period while stopped.
+    pub fn with_clock(mut self, clock: Arc<MidiClock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Returns the tempo in beats per minute, which is that of the clock given to `with_clock()` when known.
+    pub fn tempo(&self) -> f32 {
+        self.clock
+            .as_ref()
+            .and_then(|clock| clock.tempo())
+            .unwrap_or(self.bpm)
+    }
+
+    /// Changes the tempo to `bpm` beats per minute, starting with the step after the current one.
+    /// A clock given to `with_clock()` overrides it whenever that clock's tempo is known.
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.bpm = bpm;
+    }
+
+    /// Replaces the pattern of the track at index `track` with `pattern` once its current pattern ends.
+    /// Does nothing if there is no such track.
+    pub fn set_pattern(&mut self, track: usize, pattern: Pattern) {
+        if let Some(track) = self.tracks.get_mut(track) {
+            if track.next_step.is_none() {
+                track.pattern = pattern;
+            } else {
+                track.pending = Some(pattern);
+            }
+        }
+    }
+
+    /// Returns every track to its first step, to be played at `time`.
+    pub fn rewind(&mut self, time: Instant) {
+        for track in self.tracks.iter_mut() {
+            track.position = 0;
+            track.next_step = Some(time);
+            track.clock_step = None;
+        }
+    }
+
+    /// Returns the next moment at which `tick()` has something to do, or `None` if the sequencer
+    /// has not been rewound or has been silenced.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.tracks
+            .iter()
+            .flat_map(|track| [track.release_time(), track.next_step])
+            .flatten()
+            .min()
+    }
+
+    /// Adds to `out`, in order of time, every message due at or before `now`.
+    pub fn tick(&mut self, now: Instant, out: &mut Vec<SynthMsg>) {
+        if let Some(clock) = self.clock.clone() {
+            self.follow(&clock, now, out);
+            return;
+        }
+        let bpm = self.tempo();
+        while let Some(deadline) = self.next_deadline()
+            && deadline <= now
+        {
+            // Releases come before steps at the same moment, so that repeated notes are struck anew.
+            if let Some(track) = self
+                .tracks
+                .iter_mut()
+                .find(|track| track.release_time() == Some(deadline))
+            {
+                track.release(deadline, out);
+            } else if let Some(track) = self
+                .tracks
+                .iter_mut()
+                .find(|track| track.next_step == Some(deadline))
+            {
+                let step_duration = Duration::from_secs_f64(
+                    60.0 / (bpm.max(1.0) as f64 * track.pattern.steps_per_beat as f64),
+                );
+                track.take_step(deadline, step_duration, out);
+            }
+        }
+    }
+
+    /// Takes the steps that `clock` has reached by `now`, as described for `with_clock()`.
+    fn follow(&mut self, clock: &MidiClock, now: Instant, out: &mut Vec<SynthMsg>) {
+        for track in self.tracks.iter_mut() {
+            if let Some(off) = track.release_time()
+                && off <= now
+            {
+                track.release(off, out);
+            }
+        }
+        let period =
+            Duration::from_secs_f64(60.0 / (self.tempo().max(1.0) as f64 * CLOCKS_PER_BEAT as f64));
+        let (relocations, relocated_to) = clock.relocation();
+        let relocated = relocations != self.clock_relocations;
+        self.clock_relocations = relocations;
+        let position = clock.position();
+        for track in self.tracks.iter_mut() {
+            let clocks_per_step = track.clocks_per_step();
+            let len = track.pattern.steps.len().max(1) as u64;
+            if relocated {
+                // Steps that began before the new position count as taken, so that the next one is.
+                track.clock_step = relocated_to
+                    .checked_sub(1)
+                    .map(|played| played / clocks_per_step);
+                track.position = (track.clock_step.map_or(0, |step| step + 1) % len) as usize;
+            }
+            if !clock.is_running() {
+                track.release(now, out);
+                track.next_step = Some(now + period);
+                continue;
+            }
+            // A step sounds upon the `Timing Clock` that moves the position past its start.
+            let next_boundary = match position.checked_sub(1) {
+                None => 1,
+                Some(played) => {
+                    let step = played / clocks_per_step;
+                    if track.clock_step != Some(step) {
+                        // Unless this step follows the last, as usual, it is found from the position.
+                        if track.clock_step.is_none_or(|last| last + 1 != step) {
+                            track.position = (step % len) as usize;
+                        }
+                        track.take_step(now, period * clocks_per_step as u32, out);
+                        track.clock_step = Some(step);
+                    }
+                    (step + 1) * clocks_per_step + 1
+                }
+            };
+            // Waking a quarter of a clock after the last expected one leaves room for jitter.
+            let clocks_left = (next_boundary - position - 1) as u32;
+            track.next_step = Some(now + period * clocks_left + period / 4);
+        }
+    }
+
+    /// Releases every sounding note at `time` and stops every track until the next `rewind()`.
+    pub fn silence(&mut self, time: Instant, out: &mut Vec<SynthMsg>) {
+        for track in self.tracks.iter_mut() {
+            track.release(time, out);
+            track.next_step = None;
+        }
+    }
+
+    /// Starts playing on a thread of its own, sending messages to `midi_msgs`.
+    pub fn start(mut self, midi_msgs: Arc<MsgQueue<SynthMsg>>) -> SequencerHandle {
+        let commands = Arc::new(MsgQueue::new());
+        let stop = Arc::new(AtomicCell::new(false));
+        let thread_commands = commands.clone();
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            let (commands, stop) = (thread_commands, thread_stop);
+            let mut out = vec![];
+            self.rewind(Instant::now());
+            while !stop.load() {
+                let command = match self.next_deadline() {
+                    Some(deadline) => commands.pop_wait_until(&stop, deadline),
+                    None => commands.pop_wait_unless(&stop),
+                };
+                match command {
+                    Some(SequencerCommand::Pattern(track, pattern)) => {
+                        self.set_pattern(track, pattern)
+                    }
+                    Some(SequencerCommand::Tempo(bpm)) => self.set_tempo(bpm),
+                    None => {}
+                }
+                self.tick(Instant::now(), &mut out);
+                for msg in out.drain(..) {
+                    midi_msgs.push(msg);
+                }
+            }
+            self.silence(Instant::now(), &mut out);
+            for msg in out.drain(..) {
+                midi_msgs.push(msg);
+            }
+        });
+        SequencerHandle {
+            commands,
+            stop,
+            thread,
+        }
+    }
+}
+
+enum SequencerCommand {
+    Pattern(usize, Pattern),
+    Tempo(f32),
+}
+
+/// A sequencer started by `Sequencer::start()`.
+///
+/// Dropping a `SequencerHandle` leaves the sequencer playing.
+pub struct SequencerHandle {
+    commands: Arc<MsgQueue<SequencerCommand>>,
+    stop: Arc<AtomicCell<bool>>,
+    thread: JoinHandle<()>,
+}
+
+impl SequencerHandle {
+    /// Replaces the pattern of the track at index `track` with `pattern` once its current pattern ends.
+    pub fn set_pattern(&self, track: usize, pattern: Pattern) {
+        self.commands
+            .push(SequencerCommand::Pattern(track, pattern));
+    }
+
+    /// Changes the tempo to `bpm` beats per minute, starting with the step after the current one.
+    pub fn set_tempo(&self, bpm: f32) {
+        self.commands.push(SequencerCommand::Tempo(bpm));
+    }
+
+    /// Asks the sequencer to release its sounding notes and stop.
+    pub fn stop(&self) {
+        self.stop.store(true);
+        self.commands.wake();
+    }
+
+    /// Waits for the sequencer's thread to exit, whether due to `stop()` or to a panic.
+    pub fn join(self) {
+        if let Err(panic) = self.thread.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use midi_msg::{ChannelVoiceMsg, MidiMsg, SystemCommonMsg, SystemRealTimeMsg};
+
+    use crate::{
+        clock::MidiClock,
+        io::{Speaker, SynthMsg},
+        sequencer::{Pattern, Sequencer, Step, Track},
+    };
+
+    /// Returns each note in `out`, with whether it is a `Note On`.
+    fn notes(out: &[SynthMsg]) -> Vec<(u8, bool)> {
+        out.iter()
+            .map(|msg| match msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg: ChannelVoiceMsg::NoteOn { note, .. },
+                    ..
+                } => (note, true),
+                MidiMsg::ChannelVoice {
+                    msg: ChannelVoiceMsg::NoteOff { note, .. },
+                    ..
+                } => (note, false),
+                _ => panic!("unexpected message {:?}", msg.msg),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sequencer_ties_and_pattern_changes() {
+        // Quarter notes at 60 beats per minute take one second each.
+        let tied = Pattern::new(vec![
+            Step::note(60).tied(),
+            Step::note(60),
+            Step::rest(),
+            Step::note(62).tied(),
+        ])
+        .with_rate(1);
+        let mut sequencer = Sequencer::new(
+            60.0,
+            vec![
+                Track::new(tied).with_speaker(Speaker::Left),
+                Track::new(Pattern::new(vec![Step::note(48).with_gate(1.0)]).with_rate(1)),
+            ],
+        );
+        let start = Instant::now();
+        let seconds = |s: f32| start + Duration::from_secs_f32(s);
+        let mut out = vec![];
+        sequencer.rewind(start);
+        sequencer.set_pattern(1, Pattern::new(vec![Step::note(50)]).with_rate(1));
+        sequencer.tick(seconds(5.9), &mut out);
+        let events = out
+            .iter()
+            .map(|msg| match msg.msg {
+                MidiMsg::ChannelVoice {
+                    msg: ChannelVoiceMsg::NoteOn { note, .. },
+                    ..
+                } => (msg.time.unwrap(), note, true),
+                MidiMsg::ChannelVoice {
+                    msg: ChannelVoiceMsg::NoteOff { note, .. },
+                    ..
+                } => (msg.time.unwrap(), note, false),
+                _ => panic!("unexpected message {:?}", msg.msg),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                (seconds(0.0), 60, true),
+                (seconds(0.0), 48, true),
+                (seconds(1.0), 48, false),
+                (seconds(1.0), 50, true),
+                (seconds(1.5), 60, false),
+                (seconds(1.5), 50, false),
+                (seconds(2.0), 50, true),
+                (seconds(2.5), 50, false),
+                (seconds(3.0), 62, true),
+                (seconds(3.0), 50, true),
+                (seconds(3.5), 50, false),
+                (seconds(4.0), 62, false),
+                (seconds(4.0), 60, true),
+                (seconds(4.0), 50, true),
+                (seconds(4.5), 50, false),
+                (seconds(5.0), 50, true),
+                (seconds(5.5), 60, false),
+                (seconds(5.5), 50, false),
+            ]
+        );
+        assert_eq!(out[0].speaker, Speaker::Left);
+        out.clear();
+        sequencer.silence(seconds(5.9), &mut out);
+        assert!(out.is_empty());
+        assert_eq!(sequencer.next_deadline(), None);
+    }
+
+    /// Passes each of `msgs` to `clock` 20 ms apart, ticking `sequencer` after each.
+    fn drive(
+        clock: &MidiClock,
+        sequencer: &mut Sequencer,
+        time: &mut Instant,
+        msgs: &[MidiMsg],
+        out: &mut Vec<SynthMsg>,
+    ) {
+        for msg in msgs {
+            *time += Duration::from_millis(20);
+            clock.handle(msg, *time);
+            sequencer.tick(*time, out);
+        }
+    }
+
+    #[test]
+    fn test_sequencer_follows_clock_transport() {
+        // Sixteenth notes take six clocks each.
+        let clock = Arc::new(MidiClock::default());
+        let pattern = Pattern::new([60, 62, 64, 65].map(Step::note).to_vec());
+        let mut sequencer =
+            Sequencer::new(120.0, vec![Track::new(pattern)]).with_clock(clock.clone());
+        let mut time = Instant::now();
+        let mut out = vec![];
+        sequencer.rewind(time);
+        let real_time = |msg| MidiMsg::SystemRealTime { msg };
+        let clocks = |count| vec![real_time(SystemRealTimeMsg::TimingClock); count];
+        let mut drive = |msgs: &[MidiMsg], out: &mut Vec<SynthMsg>| {
+            drive(&clock, &mut sequencer, &mut time, msgs, out)
+        };
+
+        // Clocks before `Start` play nothing.
+        drive(&clocks(6), &mut out);
+        assert!(out.is_empty());
+        drive(&[real_time(SystemRealTimeMsg::Start)], &mut out);
+        drive(&clocks(13), &mut out);
+        assert_eq!(
+            notes(&out),
+            vec![(60, true), (60, false), (62, true), (62, false), (64, true)]
+        );
+
+        // `Stop` releases the sounding note, and clocks received while stopped do not advance.
+        out.clear();
+        drive(&[real_time(SystemRealTimeMsg::Stop)], &mut out);
+        drive(&clocks(12), &mut out);
+        assert_eq!(notes(&out), vec![(64, false)]);
+
+        // A `Song Position Pointer` to the tenth sixteenth note resumes from the second step on `Continue`.
+        out.clear();
+        drive(
+            &[
+                MidiMsg::SystemCommon {
+                    msg: SystemCommonMsg::SongPosition(9),
+                },
+                real_time(SystemRealTimeMsg::Continue),
+            ],
+            &mut out,
+        );
+        drive(&clocks(7), &mut out);
+        assert_eq!(notes(&out), vec![(62, true), (62, false), (64, true)]);
+
+        // `Start` returns to the first step.
+        out.clear();
+        drive(&[real_time(SystemRealTimeMsg::Start)], &mut out);
+        drive(&clocks(1), &mut out);
+        assert_eq!(notes(&out), vec![(64, false), (60, true)]);
+    }
+}
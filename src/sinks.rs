@@ -0,0 +1,374 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use cpal::{
+    FromSample, I24, SampleFormat, SizedSample, Stream, U24,
+    traits::{DeviceTrait, StreamTrait},
+};
+use hound::{WavSpec, WavWriter};
+
+use crate::{
+    io::{OutputConfig, ResolvedOutput},
+    synth::Renderer,
+};
+
+/// Destination for the audio rendered by an output thread.
+///
+/// Whenever the synthesizer sound changes, the output thread passes a new `Renderer` to `play()`.
+/// The sink should then pull audio from that `Renderer` at `sample_rate()`, in real time, until
+/// `play()` is called again or the sink is dropped.
+pub trait AudioSink {
+    /// Returns the sample rate, in Hz, at which this sink consumes audio.
+    fn sample_rate(&self) -> f64;
+
+    /// Begins consuming audio from `renderer`, replacing any `Renderer` previously passed to `play()`.
+    fn play(&mut self, renderer: Renderer) -> anyhow::Result<()>;
+}
+
+/// Plays audio through an audio output device using [cpal](https://crates.io/crates/cpal).
+pub struct CpalSink {
+    output: ResolvedOutput,
+    stream: Option<Stream>,
+}
+
+impl CpalSink {
+    /// Opens the device selected by `output`, returning an error if it cannot satisfy `output`.
+    pub fn new(output: &OutputConfig) -> anyhow::Result<Self> {
+        Ok(Self::from_resolved(output.resolve()?))
+    }
+
+    pub(crate) fn from_resolved(output: ResolvedOutput) -> Self {
+        Self {
+            output,
+            stream: None,
+        }
+    }
+
+    fn build_stream<T: SizedSample + FromSample<f32>>(
+        &self,
+        mut renderer: Renderer,
+    ) -> anyhow::Result<Stream> {
+        let channels = self.output.config.channels as usize;
+        let err_fn = |err| eprintln!("Error on stream: {err}");
+        self.output
+            .device
+            .build_output_stream(
+                &self.output.config,
+                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                    renderer.render_interleaved(data, channels)
+                },
+                err_fn,
+                None,
+            )
+            .map_err(anyhow::Error::from)
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn sample_rate(&self) -> f64 {
+        self.output.config.sample_rate as f64
+    }
+
+    fn play(&mut self, renderer: Renderer) -> anyhow::Result<()> {
+        // Some devices support only one stream at a time, so the old one must close first.
+        self.stream = None;
+        let stream = match self.output.sample_format {
+            SampleFormat::I8 => self.build_stream::<i8>(renderer),
+            SampleFormat::I16 => self.build_stream::<i16>(renderer),
+            SampleFormat::I24 => self.build_stream::<I24>(renderer),
+            SampleFormat::I32 => self.build_stream::<i32>(renderer),
+            SampleFormat::I64 => self.build_stream::<i64>(renderer),
+            SampleFormat::U8 => self.build_stream::<u8>(renderer),
+            SampleFormat::U16 => self.build_stream::<u16>(renderer),
+            SampleFormat::U24 => self.build_stream::<U24>(renderer),
+            SampleFormat::U32 => self.build_stream::<u32>(renderer),
+            SampleFormat::U64 => self.build_stream::<u64>(renderer),
+            SampleFormat::F32 => self.build_stream::<f32>(renderer),
+            SampleFormat::F64 => self.build_stream::<f64>(renderer),
+            sample_format => bail!("Unsupported sample format '{sample_format}'"),
+        }?;
+        stream.play()?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+}
+
+/// Default number of frames rendered at a time by `NullSink` and `WavFileSink`.
+pub const DEFAULT_BUFFER_SIZE: usize = 512;
+
+/// Renders audio in real time and discards it. This keeps a synthesizer responsive to MIDI messages
+/// on machines with no audio hardware.
+pub struct NullSink {
+    paced: PacedSink,
+}
+
+impl NullSink {
+    /// Creates a sink consuming audio at `sample_rate` Hz, `DEFAULT_BUFFER_SIZE` frames at a time.
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            paced: PacedSink::new(sample_rate, DEFAULT_BUFFER_SIZE, |_, _| {}),
+        }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> f64 {
+        self.paced.sample_rate
+    }
+
+    fn play(&mut self, renderer: Renderer) -> anyhow::Result<()> {
+        self.paced.play(renderer);
+        Ok(())
+    }
+}
+
+/// Renders audio in real time and records it as a 32-bit floating-point stereo WAV file.
+/// The file is finalized when the sink is dropped. A synthesizer drops its sink before its thread exits,
+/// so the file is complete once `SynthHandle::join()` returns.
+pub struct WavFileSink {
+    paced: PacedSink,
+}
+
+impl WavFileSink {
+    /// Creates the file at `path`, to which audio will be written at `sample_rate` Hz.
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> anyhow::Result<Self> {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = Some(WavWriter::create(path, spec)?);
+        let paced = PacedSink::new(
+            sample_rate as f64,
+            DEFAULT_BUFFER_SIZE,
+            move |left, right| {
+                if let Some(wav) = writer.as_mut() {
+                    let result = left
+                        .iter()
+                        .zip(right.iter())
+                        .try_for_each(|(l, r)| wav.write_sample(*l).and(wav.write_sample(*r)));
+                    if let Err(err) = result {
+                        eprintln!("Error writing WAV file: {err}");
+                        writer = None;
+                    }
+                }
+            },
+        );
+        Ok(Self { paced })
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn sample_rate(&self) -> f64 {
+        self.paced.sample_rate
+    }
+
+    fn play(&mut self, renderer: Renderer) -> anyhow::Result<()> {
+        self.paced.play(renderer);
+        Ok(())
+    }
+}
+
+type SharedRenderer = Arc<Mutex<Option<Renderer>>>;
+
+/// Pulls audio from the current `Renderer` on a thread of its own, one buffer at a time, at the pace
+/// an audio device would. Dropping the `PacedSink` waits for the thread to consume its last buffer and exit.
+struct PacedSink {
+    sample_rate: f64,
+    renderer: SharedRenderer,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PacedSink {
+    fn new<F: FnMut(&[f32], &[f32]) + Send + 'static>(
+        sample_rate: f64,
+        buffer_size: usize,
+        mut consume: F,
+    ) -> Self {
+        let renderer: SharedRenderer = Arc::new(Mutex::new(None));
+        let weak = Arc::downgrade(&renderer);
+        let thread = std::thread::spawn(move || {
+            let period = Duration::from_secs_f64(buffer_size as f64 / sample_rate);
+            let mut left = vec![0.0; buffer_size];
+            let mut right = vec![0.0; buffer_size];
+            let mut next_buffer = Instant::now();
+            while render_from(&weak, &mut left, &mut right) {
+                consume(&left, &right);
+                next_buffer += period;
+                std::thread::sleep(next_buffer.saturating_duration_since(Instant::now()));
+            }
+        });
+        Self {
+            sample_rate,
+            renderer,
+            thread: Some(thread),
+        }
+    }
+
+    fn play(&mut self, renderer: Renderer) {
+        *self.renderer.lock().unwrap() = Some(renderer);
+    }
+}
+
+impl Drop for PacedSink {
+    fn drop(&mut self) {
+        // Releasing the only strong reference tells the thread to exit, dropping its consumer.
+        self.renderer = Arc::new(Mutex::new(None));
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            eprintln!("Audio sink thread panicked");
+        }
+    }
+}
+
+/// Renders into `left` and `right`, or fills them with silence if no `Renderer` has started playing.
+/// Returns `false` once the owner of `renderer` has been dropped.
+fn render_from(
+    renderer: &Weak<Mutex<Option<Renderer>>>,
+    left: &mut [f32],
+    right: &mut [f32],
+) -> bool {
+    let Some(renderer) = renderer.upgrade() else {
+        return false;
+    };
+    match renderer.lock().unwrap().as_mut() {
+        Some(renderer) => renderer.render(left, right),
+        None => {
+            left.fill(0.0);
+            right.fill(0.0);
+        }
+    }
+    true
+}
+
+#[derive(Clone)]
+/// Renders audio only when asked, into buffers supplied by the caller. Clones share the same `Renderer`,
+/// so one clone can be passed to an output thread while another pulls audio from it.
+///
+/// Useful for tests, where audio need not be produced in real time. Events are still placed by the time
+/// they reach the synthesizer, so the audio depends on when it is rendered. Offline rendering, which must
+/// not depend on wall-clock timing, should use a `Synth` instead.
+pub struct BufferSink {
+    sample_rate: f64,
+    renderer: SharedRenderer,
+}
+
+impl BufferSink {
+    /// Creates a sink rendering audio at `sample_rate` Hz.
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate,
+            renderer: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Fills `left` and `right` with the next frames of audio, or with silence if no `Renderer`
+    /// has started playing. Both slices must have the same length.
+    pub fn render(&self, left: &mut [f32], right: &mut [f32]) {
+        render_from(&Arc::downgrade(&self.renderer), left, right);
+    }
+
+    /// Returns the next `frames` frames of audio as `(left, right)` pairs.
+    pub fn render_frames(&self, frames: usize) -> Vec<(f32, f32)> {
+        let mut left = vec![0.0; frames];
+        let mut right = vec![0.0; frames];
+        self.render(&mut left, &mut right);
+        left.into_iter().zip(right).collect()
+    }
+}
+
+impl AudioSink for BufferSink {
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    fn play(&mut self, renderer: Renderer) -> anyhow::Result<()> {
+        *self.renderer.lock().unwrap() = Some(renderer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg};
+
+    use crate::{
+        io::{Speaker, SynthBuilder, SynthMsg},
+        sinks::{BufferSink, WavFileSink},
+        sound_builders::SharedProgramTable,
+        sounds::options,
+    };
+
+    /// Returns `true` once `ready()` does, or `false` if it has not within five seconds.
+    fn wait_for<F: FnMut() -> bool>(mut ready: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !ready() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    #[test]
+    fn test_buffer_sink_renders_notes() {
+        let sink = BufferSink::new(44100.0);
+        let synth = SynthBuilder::<4>::new(Arc::new(SharedProgramTable::new(options())))
+            .with_sink(sink.clone())
+            .start()
+            .unwrap();
+        let silence = sink.render_frames(4410);
+        assert!(silence.iter().all(|(l, r)| *l == 0.0 && *r == 0.0));
+
+        synth.send(SynthMsg::new(
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg: ChannelVoiceMsg::NoteOn {
+                    note: 60,
+                    velocity: 127,
+                },
+            },
+            Speaker::Both,
+        ));
+        // The note reaches the renderer once the relay and output threads have passed it along.
+        assert!(wait_for(|| {
+            sink.render_frames(441)
+                .iter()
+                .any(|(l, r)| *l != 0.0 && *r != 0.0)
+        }));
+        synth.stop();
+        synth.join();
+    }
+
+    #[test]
+    fn test_wav_file_finalized_on_drop() {
+        let path = std::env::temp_dir().join(format!("midi_fundsp_{}.wav", std::process::id()));
+        let synth = SynthBuilder::<4>::new(Arc::new(SharedProgramTable::new(options())))
+            .with_sink(WavFileSink::create(&path, 44100).unwrap())
+            .start()
+            .unwrap();
+        // The file grows beyond its header once the sink has written some frames.
+        assert!(wait_for(
+            || std::fs::metadata(&path).is_ok_and(|file| file.len() > 4096)
+        ));
+        synth.stop();
+        synth.join();
+        let reader = hound::WavReader::open(&path).unwrap();
+        assert!(reader.len() > 0);
+        assert_eq!(reader.len() as usize, reader.into_samples::<f32>().count());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,11 +1,14 @@
+use std::{ops::RangeInclusive, sync::Arc};
+
+use arc_swap::ArcSwap;
 use fundsp::{
     math::{clamp01, xerp},
     net::Net,
-    prelude::AudioUnit,
+    prelude::{AudioUnit, FrameAdd, FrameMul},
     prelude64::{adsr_live, envelope2, moog_q},
 };
 
-use crate::{SharedMidiState, SynthFunc};
+use crate::{MAX_MIDI_VALUE, SharedMidiState, SynthFunc};
 
 #[macro_export]
 /// Convenience macro to build a `ProgramTable`. Given a sequence of tuples of `&str` objects
@@ -20,6 +23,68 @@ pub const NUM_PROGRAM_SLOTS: usize = 2_usize.pow(7);
 /// Convenience type alias for MIDI program tables.
 pub type ProgramTable = Vec<(String, SynthFunc)>;
 
+/// A `ProgramTable` that one thread may edit while synthesizer threads read from it.
+///
+/// Reads never block: `snapshot()` and `get()` atomically load the most recently published
+/// version of the table. Edits made through `replace()` or `update()` publish a new version,
+/// which players pick up on their next MIDI `Program Change` message.
+pub struct SharedProgramTable {
+    current: ArcSwap<ProgramTable>,
+}
+
+impl SharedProgramTable {
+    /// Publishes `table` as the initial version.
+    pub fn new(table: ProgramTable) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(table),
+        }
+    }
+
+    /// Returns the current version of the table. Later edits do not affect the returned snapshot.
+    pub fn snapshot(&self) -> Arc<ProgramTable> {
+        self.current.load_full()
+    }
+
+    /// Returns the `SynthFunc` stored at index `program`, if any.
+    pub fn get(&self, program: usize) -> Option<SynthFunc> {
+        self.current
+            .load()
+            .get(program)
+            .map(|(_, synth)| synth.clone())
+    }
+
+    /// Returns the number of programs in the current version of the table.
+    pub fn len(&self) -> usize {
+        self.current.load().len()
+    }
+
+    /// Returns `true` if the current version of the table has no programs.
+    pub fn is_empty(&self) -> bool {
+        self.current.load().is_empty()
+    }
+
+    /// Publishes `table` in place of the current version.
+    pub fn replace(&self, table: ProgramTable) {
+        self.current.store(Arc::new(table));
+    }
+
+    /// Publishes a copy of the current version as modified by `edit`. If another edit is published
+    /// meanwhile, `edit` is applied again to a copy of that version, so no edit is lost.
+    pub fn update<F: FnMut(&mut ProgramTable)>(&self, mut edit: F) {
+        self.current.rcu(|current| {
+            let mut table = current.as_ref().clone();
+            edit(&mut table);
+            table
+        });
+    }
+}
+
+impl From<ProgramTable> for SharedProgramTable {
+    fn from(table: ProgramTable) -> Self {
+        Self::new(table)
+    }
+}
+
 /// Pipes a pitch into `synth`, then modulates the output volume depending on MIDI status.
 pub fn simple_sound(state: &SharedMidiState, synth: Box<dyn AudioUnit>) -> Box<dyn AudioUnit> {
     let control = state.control_var();
@@ -29,6 +94,89 @@ pub fn simple_sound(state: &SharedMidiState, synth: Box<dyn AudioUnit>) -> Box<d
     )
 }
 
+#[derive(Clone)]
+/// One of the sounds of a program built by `velocity_layers()`, played in response to a range of velocities.
+pub struct VelocityLayer {
+    /// Velocities at which the layer plays at full volume.
+    pub velocities: RangeInclusive<u8>,
+    /// Number of velocities over which the layer fades in and out at each end of `velocities`, centered
+    /// on the boundary with the adjacent range. If `0`, the layer switches on and off abruptly.
+    pub crossfade: u8,
+    /// The sound of the layer.
+    pub sound: SynthFunc,
+}
+
+impl VelocityLayer {
+    /// Creates a layer that plays `sound` in response to `velocities`.
+    pub fn new(velocities: RangeInclusive<u8>, sound: SynthFunc) -> Self {
+        Self {
+            velocities,
+            crossfade: 0,
+            sound,
+        }
+    }
+
+    /// Fades the layer in and out over `crossfade` velocities at each end of its range.
+    pub fn with_crossfade(mut self, crossfade: u8) -> Self {
+        self.crossfade = crossfade;
+        self
+    }
+
+    /// Returns the volume of the layer at `velocity`, which may fall between two MIDI velocities.
+    /// The ends of the MIDI velocity range never fade.
+    pub fn gain(&self, velocity: f32) -> f32 {
+        let (low, high) = (*self.velocities.start(), *self.velocities.end());
+        // `distance` is how far `velocity` lies inside the boundary between this range and the next.
+        let ramp = |distance: f32| {
+            if self.crossfade == 0 {
+                if distance >= 0.0 { 1.0 } else { 0.0 }
+            } else {
+                clamp01(distance / self.crossfade as f32 + 0.5)
+            }
+        };
+        let fade_in = if low <= 1 {
+            1.0
+        } else {
+            ramp(velocity - (low as f32 - 0.5))
+        };
+        let fade_out = if high >= MAX_MIDI_VALUE {
+            1.0
+        } else {
+            ramp(high as f32 + 0.5 - velocity)
+        };
+        fade_in * fade_out
+    }
+}
+
+/// Combines `layers` into a single sound, each of which plays only in response to its own range of
+/// `Note On` velocities. Layers whose ranges are adjacent and share a crossfade blend smoothly into each
+/// other, such as soft, medium, and hard piano tones.
+///
+/// Velocities are measured after conversion by the player's `VelocityCurve`. Every layer runs
+/// continuously, so each layer adds to the processing cost of every voice.
+pub fn velocity_layers(layers: Vec<VelocityLayer>) -> SynthFunc {
+    Arc::new(move |state: &SharedMidiState| {
+        let layered = layers
+            .iter()
+            .map(|layer| {
+                let gain = layer.clone();
+                Net::binary(
+                    Net::wrap((layer.sound)(state)),
+                    Net::wrap(Box::new(
+                        state.velocity_var()
+                            >> envelope2(move |_, v| {
+                                gain.gain((v * MAX_MIDI_VALUE as f64) as f32) as f64
+                            }),
+                    )),
+                    FrameMul::new(),
+                )
+            })
+            .reduce(|mix, layer| Net::binary(mix, layer, FrameAdd::new()))
+            .expect("velocity_layers() requires at least one layer");
+        Box::new(layered)
+    })
+}
+
 #[derive(Copy, Clone, Debug)]
 /// Represents ADSR (Attack/Decay/Sustain/Release) settings for the purpose of generating MIDI-ready sounds.
 pub struct Adsr {
@@ -86,3 +234,93 @@ impl Adsr {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::sound_builders::{ProgramTable, SharedProgramTable, VelocityLayer, velocity_layers};
+    use crate::sounds::{adsr_saw, adsr_sine, simple_triangle};
+    use crate::{SAMPLE_RATE, SharedMidiState, SynthFunc};
+
+    #[test]
+    fn test_shared_program_table_edits() {
+        let table = SharedProgramTable::new(program_table![
+            ("Triangle", simple_triangle),
+            ("Sine", adsr_sine)
+        ]);
+        let before = table.snapshot();
+        table.update(|t| t.push(("Saw".to_owned(), Arc::new(adsr_saw))));
+        assert_eq!(before.len(), 2);
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.snapshot()[2].0, "Saw");
+        assert!(table.get(2).is_some());
+        assert!(table.get(3).is_none());
+        table.replace(program_table![("Sine", adsr_sine)]);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_shared_program_table_swap_while_reading() {
+        // Version `n` holds `n % 4 + 1` copies of a program named `n`, so a torn or freed read shows up as
+        // a mismatched length or name.
+        let version = |n: usize| {
+            (0..n % 4 + 1)
+                .map(|_| (n.to_string(), Arc::new(simple_triangle) as SynthFunc))
+                .collect::<ProgramTable>()
+        };
+        let table = SharedProgramTable::new(version(0));
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut latest = 0;
+                    while latest < 1000 {
+                        let snapshot = table.snapshot();
+                        let n = snapshot[0].0.parse::<usize>().unwrap();
+                        assert_eq!(snapshot.len(), n % 4 + 1);
+                        assert!(snapshot.iter().all(|(name, _)| *name == snapshot[0].0));
+                        assert!(n >= latest);
+                        latest = n;
+                    }
+                });
+            }
+            for n in 1..=1000 {
+                if n % 2 == 0 {
+                    table.replace(version(n));
+                } else {
+                    table.update(|t| *t = version(n));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_velocity_layers() {
+        let soft = VelocityLayer::new(1..=63, Arc::new(adsr_sine)).with_crossfade(16);
+        let hard = VelocityLayer::new(64..=127, Arc::new(adsr_saw)).with_crossfade(16);
+        for (velocity, soft_gain, hard_gain) in [
+            (1.0, 1.0, 0.0),
+            (40.0, 1.0, 0.0),
+            (59.5, 0.75, 0.25),
+            (63.5, 0.5, 0.5),
+            (80.0, 0.0, 1.0),
+            (127.0, 0.0, 1.0),
+        ] {
+            assert_eq!(soft.gain(velocity), soft_gain);
+            assert_eq!(hard.gain(velocity), hard_gain);
+        }
+        let switched = VelocityLayer::new(64..=127, Arc::new(adsr_saw));
+        assert_eq!(switched.gain(63.0), 0.0);
+        assert_eq!(switched.gain(64.0), 1.0);
+
+        let state = SharedMidiState::default();
+        let mut sound = velocity_layers(vec![soft, hard])(&state);
+        assert_eq!(sound.outputs(), 1);
+        sound.reset();
+        sound.set_sample_rate(SAMPLE_RATE);
+        // The envelopes start upon the first `Note On` that follows silence.
+        assert!((0..100).all(|_| sound.get_mono() == 0.0));
+        state.on(60, 100);
+        assert!((0..1000).any(|_| sound.get_mono() != 0.0));
+    }
+}
@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use crate::sound_builders::{Adsr, ProgramTable, simple_sound};
+use crate::sound_builders::{Adsr, ProgramTable, VelocityLayer, simple_sound, velocity_layers};
 use crate::{SharedMidiState, program_table};
 use fundsp::net::Net;
 use fundsp::prelude::{
@@ -34,7 +34,8 @@ pub fn options() -> ProgramTable {
         ("Xylophone", xylophone),
         ("Clavichord (Sharp)", clavichord_sharp),
         ("Clavichord (Soft)", clavichord_soft),
-        ("Guitar-ish", guitarish)
+        ("Guitar-ish", guitarish),
+        ("Velocity Sine/Saw", velocity_sine_saw)
     ]
 }
 
@@ -306,3 +307,11 @@ pub fn guitarish(state: &SharedMidiState) -> Box<dyn AudioUnit> {
         >> lowpass_hz::<f32>(3000.0, 0.5);
     state.assemble_pitched_sound(Box::new(mix), adsr.boxed(state))
 }
+
+/// A sine wave when played softly, crossfading into a sawtooth wave when played hard.
+pub fn velocity_sine_saw(state: &SharedMidiState) -> Box<dyn AudioUnit> {
+    velocity_layers(vec![
+        VelocityLayer::new(1..=63, Arc::new(adsr_sine)).with_crossfade(32),
+        VelocityLayer::new(64..=127, Arc::new(adsr_saw)).with_crossfade(32),
+    ])(state)
+}
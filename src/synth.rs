@@ -0,0 +1,1122 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use bare_metal_modulo::*;
+use cpal::{FromSample, Sample};
+use crossbeam_queue::SegQueue;
+use crossbeam_utils::atomic::AtomicCell;
+use fundsp::{
+    math::midi_hz,
+    net::Net,
+    prelude::{AudioUnit, FrameAdd, FrameMul},
+    prelude64::{pass, shared, var},
+    shared::Shared,
+};
+use midi_msg::{Channel, ChannelModeMsg, ChannelVoiceMsg, MidiMsg, SystemRealTimeMsg};
+
+use crate::{
+    NUM_MIDI_VALUES, SharedMidiState, SynthFunc,
+    arpeggiator::{ArpEngine, Arpeggiator},
+    clock::MidiClock,
+    io::{MsgQueue, Speaker, SynthMsg},
+    sinks::AudioSink,
+    sound_builders::SharedProgramTable,
+    tunings::{KeyTable, Tuning, adaptive::AdaptiveJust, mts::retuned_keys},
+    velocity::VelocityCurve,
+    zones::Zone,
+};
+
+/// A synthesizer that renders into buffers supplied by its owner. It neither spawns threads nor opens devices,
+/// making it suitable for embedding in plugin frameworks and game engines.
+///
+/// Synthesizer sounds may be selected with MIDI `Program Change` messages that reference sounds stored in
+/// `program_table`. The constant value `N` is the number of distinct sounds it can emit at once, as with
+/// `SynthBuilder`.
+pub struct Synth<const N: usize> {
+    player: StereoPlayer<N>,
+    renderer: Renderer,
+    arpeggiator: Option<ArpEngine>,
+    origin: Instant,
+    frames_rendered: u64,
+}
+
+impl<const N: usize> Synth<N> {
+    /// Creates a synthesizer that renders audio at `sample_rate` Hz using sounds from `program_table`.
+    pub fn new(program_table: Arc<SharedProgramTable>, sample_rate: f64) -> Self {
+        Self::from_player(StereoPlayer::new(program_table), sample_rate)
+    }
+
+    /// Creates a synthesizer like `new()`, but whose sounds are played by `zones` rather than by one part
+    /// for each `Speaker`. Returns an error if `zones` is empty or refers to a program not in `program_table`.
+    pub fn with_zones(
+        program_table: Arc<SharedProgramTable>,
+        sample_rate: f64,
+        zones: Vec<Zone>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::from_player(
+            StereoPlayer::with_zones(program_table, zones)?,
+            sample_rate,
+        ))
+    }
+
+    fn from_player(player: StereoPlayer<N>, sample_rate: f64) -> Self {
+        let renderer = player.renderer(sample_rate);
+        Self {
+            player,
+            renderer,
+            arpeggiator: None,
+            origin: Instant::now(),
+            frames_rendered: 0,
+        }
+    }
+
+    /// Returns the sample rate, in Hz, of the rendered audio.
+    pub fn sample_rate(&self) -> f64 {
+        self.renderer.sample_rate()
+    }
+
+    /// Changes the sample rate, in Hz, of the rendered audio.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.restart(sample_rate);
+    }
+
+    /// Changes how MIDI notes are converted to pitches. Defaults to equal temperament.
+    pub fn set_midi_to_hz(&mut self, midi_to_hz: fn(f32) -> f32) {
+        self.set_tuning(&midi_to_hz);
+    }
+
+    /// Retunes every key according to `tuning`, taking effect upon the next `Note On`.
+    /// Subsequent changes to the state of `tuning` take effect only if it is passed here again.
+    pub fn set_tuning(&mut self, tuning: &dyn Tuning) {
+        self.player.set_tuning(tuning);
+    }
+
+    /// Retunes held notes by `adaptive` as the harmony changes, or stops doing so if `None`.
+    pub fn set_adaptive_tuning(&mut self, adaptive: Option<AdaptiveJust>) {
+        self.player.set_adaptive_tuning(adaptive);
+    }
+
+    /// Converts `Note On` velocities into volumes with `curve`, except in zones with curves of their own.
+    /// Defaults to `VelocityCurve::Linear`.
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.player.set_velocity_curve(curve);
+    }
+
+    /// Transposes notes played through `speaker` by `semitones`, taking effect upon the next `Note On`.
+    /// The transposition for `Speaker::Both` applies to both speakers, in addition to their own.
+    pub fn set_transpose(&mut self, speaker: Speaker, semitones: i8) {
+        self.player.transposition.set_semitones(speaker, semitones);
+    }
+
+    /// Shifts notes played through `speaker` by `octaves`, taking effect upon the next `Note On`.
+    /// The shift for `Speaker::Both` applies to both speakers, in addition to their own.
+    pub fn set_octave_shift(&mut self, speaker: Speaker, octaves: i8) {
+        self.player.transposition.set_octaves(speaker, octaves);
+    }
+
+    /// Passes incoming notes through `arpeggiator`, which plays the notes of held chords one at a time,
+    /// or stops doing so if `None`. Steps fall on the frames at which they are due.
+    pub fn set_arpeggiator(&mut self, arpeggiator: Option<Arpeggiator>) {
+        self.arpeggiator = arpeggiator.map(ArpEngine::new);
+    }
+
+    /// Returns the `MidiClock` following the `Timing Clock`, `Start`, `Stop`, `Continue`, and
+    /// `Song Position Pointer` messages handled so far.
+    pub fn clock(&self) -> Arc<MidiClock> {
+        self.player.clock()
+    }
+
+    /// Handles `msg` for both speakers at the start of the next call to `process()`.
+    pub fn handle(&mut self, msg: &MidiMsg) {
+        self.handle_at(msg, Speaker::Both, 0);
+    }
+
+    /// Handles `msg` for `speaker` at index `frame` of the buffers passed to the next call to `process()`.
+    /// Messages for the same buffer must be handled in order of `frame`.
+    pub fn handle_at(&mut self, msg: &MidiMsg, speaker: Speaker, frame: usize) {
+        let time = self.buffer_start() + self.renderer.scheduler.frame_period * frame as u32;
+        match self.arpeggiator.as_mut() {
+            None => self.decode(speaker, msg, time),
+            Some(arp) => {
+                let mut out = vec![];
+                arp.tick(time, &mut out);
+                arp.handle(
+                    SynthMsg {
+                        msg: msg.clone(),
+                        speaker,
+                        time: Some(time),
+                    },
+                    time,
+                    &mut out,
+                );
+                self.decode_all(out, time);
+            }
+        }
+    }
+
+    /// Fills `out_left` and `out_right` with the next frames of audio for the corresponding speakers.
+    /// Both slices must have the same length.
+    pub fn process(&mut self, out_left: &mut [f32], out_right: &mut [f32]) {
+        assert_eq!(out_left.len(), out_right.len());
+        let last = self.buffer_start()
+            + self.renderer.scheduler.frame_period * (out_left.len() as u32).saturating_sub(1);
+        if let Some(arp) = self.arpeggiator.as_mut() {
+            let mut out = vec![];
+            arp.tick(last, &mut out);
+            self.decode_all(out, last);
+        }
+        self.renderer.scheduler.buffer_start = self.buffer_start();
+        self.renderer.render_frames(out_left, out_right);
+        self.frames_rendered += out_left.len() as u64;
+    }
+
+    fn decode(&mut self, speaker: Speaker, msg: &MidiMsg, time: Instant) {
+        if self.player.decode(speaker, msg, time) == Some(RelayedMessage::SynthChange) {
+            self.restart(self.sample_rate());
+        }
+    }
+
+    fn decode_all(&mut self, msgs: Vec<SynthMsg>, time: Instant) {
+        for msg in msgs {
+            self.decode(msg.speaker, &msg.msg, msg.time.unwrap_or(time));
+        }
+    }
+
+    fn buffer_start(&self) -> Instant {
+        self.origin
+            + Duration::from_secs_f64(self.frames_rendered as f64 / self.renderer.sample_rate())
+    }
+
+    fn restart(&mut self, sample_rate: f64) {
+        let buffer_start = self.buffer_start();
+        self.renderer = self.player.renderer(sample_rate);
+        self.origin = buffer_start;
+        self.frames_rendered = 0;
+    }
+}
+
+/// Renders the sound of a synthesizer, applying each MIDI event at the audio frame corresponding to its time.
+/// An `AudioSink` obtains its audio from a `Renderer`.
+pub struct Renderer {
+    sound: Net,
+    scheduler: EventScheduler,
+}
+
+impl Renderer {
+    /// Returns the sample rate, in Hz, of the rendered audio.
+    pub fn sample_rate(&self) -> f64 {
+        1.0 / self.scheduler.frame_period.as_secs_f64()
+    }
+
+    /// Fills `left` and `right` with the next frames of audio for the corresponding speakers.
+    /// Both slices must have the same length.
+    pub fn render(&mut self, left: &mut [f32], right: &mut [f32]) {
+        assert_eq!(left.len(), right.len());
+        self.scheduler.start_buffer(left.len());
+        self.render_frames(left, right);
+    }
+
+    fn render_frames(&mut self, left: &mut [f32], right: &mut [f32]) {
+        for (i, (left, right)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            self.scheduler.apply_events(i);
+            (*left, *right) = self.sound.get_stereo();
+        }
+    }
+
+    /// Fills `output` with the next frames of audio, interleaved across `channels` channels.
+    /// Even-numbered channels receive the left speaker's sound, and odd-numbered channels the right's.
+    pub fn render_interleaved<T: Sample + FromSample<f32>>(
+        &mut self,
+        output: &mut [T],
+        channels: usize,
+    ) {
+        self.scheduler.start_buffer(output.len() / channels);
+        for (i, frame) in output.chunks_mut(channels).enumerate() {
+            self.scheduler.apply_events(i);
+            let sample = self.sound.get_stereo();
+            let left: T = Sample::from_sample::<f32>(sample.0);
+            let right: T = Sample::from_sample::<f32>(sample.1);
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = if channel & 1 == 0 { left } else { right };
+            }
+        }
+    }
+}
+
+/// Applies `VoiceEvent`s to the voices of a `StereoPlayer` at the audio frames corresponding to their times.
+///
+/// Each buffer renders the events timed during the span of one buffer before the callback began. Every event
+/// is thus delayed by the same latency of one buffer, rather than by however long it happened to wait for the
+/// next callback.
+struct EventScheduler {
+    events: Arc<SegQueue<VoiceEvent>>,
+    deferred: Arc<SegQueue<VoiceEvent>>,
+    voices: Vec<Voices>,
+    glides: Vec<Glide>,
+    pending: Option<VoiceEvent>,
+    frame_period: Duration,
+    buffer_start: Instant,
+}
+
+impl EventScheduler {
+    fn start_buffer(&mut self, frames: usize) {
+        let now = Instant::now();
+        self.buffer_start = now
+            .checked_sub(self.frame_period * frames as u32)
+            .unwrap_or(now);
+    }
+
+    fn apply_events(&mut self, frame: usize) {
+        let frame_time = self.buffer_start + self.frame_period * frame as u32;
+        while let Some(event) = self
+            .pending
+            .take()
+            .or_else(|| self.deferred.pop())
+            .or_else(|| self.events.pop())
+        {
+            if event.time > frame_time {
+                self.pending = Some(event);
+                break;
+            }
+            self.apply(event.part, event.change);
+        }
+        self.advance_glides();
+    }
+
+    fn apply(&mut self, part: usize, change: VoiceChange) {
+        match change {
+            VoiceChange::On { voice, .. } => self.end_glide(part, voice),
+            VoiceChange::Glide {
+                voice,
+                hz,
+                duration,
+            } => {
+                self.end_glide(part, voice);
+                let frames = (duration.as_secs_f64() / self.frame_period.as_secs_f64()) as u32;
+                let from = self.voices[part].states[voice].hz();
+                // A silent voice has no pitch from which to glide.
+                if frames > 0 && from > 0.0 {
+                    self.glides.push(Glide {
+                        part,
+                        voice,
+                        from,
+                        to: hz,
+                        frame: 0,
+                        frames,
+                    });
+                    return;
+                }
+            }
+            _ => {}
+        }
+        self.voices[part].apply(change);
+    }
+
+    fn end_glide(&mut self, part: usize, voice: usize) {
+        self.glides
+            .retain(|glide| glide.part != part || glide.voice != voice);
+    }
+
+    fn advance_glides(&mut self) {
+        let voices = &self.voices;
+        self.glides.retain_mut(|glide| {
+            glide.frame += 1;
+            let progress = glide.frame as f32 / glide.frames as f32;
+            voices[glide.part].states[glide.voice]
+                .set_hz(glide.from * (glide.to / glide.from).powf(progress));
+            glide.frame < glide.frames
+        });
+    }
+}
+
+impl Drop for EventScheduler {
+    fn drop(&mut self) {
+        // The next `Renderer` must apply this event before any remaining in `events`.
+        if let Some(event) = self.pending.take() {
+            self.deferred.push(event);
+        }
+    }
+}
+
+/// A voice moving from one frequency to another in equal steps of pitch, one step per frame.
+struct Glide {
+    part: usize,
+    voice: usize,
+    from: f32,
+    to: f32,
+    frame: u32,
+    frames: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+/// A `VoiceChange` for the `MonoPlayer` at index `part` of a `StereoPlayer`, timed to take effect at `time`.
+struct VoiceEvent {
+    time: Instant,
+    part: usize,
+    change: VoiceChange,
+}
+
+#[derive(Copy, Clone, Debug)]
+/// A change to the `Shared` variables controlling the sounds of a `MonoPlayer`.
+enum VoiceChange {
+    On {
+        voice: usize,
+        pitch: u8,
+        volume: f32,
+    },
+    Off {
+        voice: usize,
+    },
+    Bend(u16),
+    MasterVolume(f32),
+    Tune {
+        key: u8,
+        hz: f32,
+    },
+    Retune {
+        voice: usize,
+        pitch: u8,
+    },
+    Glide {
+        voice: usize,
+        hz: f32,
+        duration: Duration,
+    },
+}
+
+#[derive(Clone)]
+/// The `Shared` variables controlling the sounds of a `MonoPlayer`.
+struct Voices {
+    states: Vec<SharedMidiState>,
+    master_volume: Shared,
+    keys: Arc<KeyTable>,
+}
+
+impl Voices {
+    fn apply(&self, change: VoiceChange) {
+        match change {
+            VoiceChange::On {
+                voice,
+                pitch,
+                volume,
+            } => self.states[voice].on_at_volume(pitch, volume),
+            VoiceChange::Off { voice } => self.states[voice].off(),
+            VoiceChange::Bend(bend) => {
+                for state in self.states.iter() {
+                    state.bend(bend);
+                }
+            }
+            VoiceChange::MasterVolume(volume) => self.master_volume.set_value(volume),
+            VoiceChange::Tune { key, hz } => self.keys.set(key, hz),
+            VoiceChange::Retune { voice, pitch } => self.states[voice].retune(pitch),
+            VoiceChange::Glide { voice, hz, .. } => self.states[voice].set_hz(hz),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+/// Messages that require action beyond updating the `Shared` variables of a `MonoPlayer`.
+enum RelayedMessage {
+    SynthChange,
+    SystemReset,
+}
+
+/// Semitone transpositions and octave shifts for each `Speaker`, which may change while a synthesizer plays.
+/// The settings for `Speaker::Both` apply to every part, in addition to the settings for the speaker of its zone.
+#[derive(Default)]
+pub(crate) struct Transposition {
+    semitones: [AtomicCell<i8>; 3],
+    octaves: [AtomicCell<i8>; 3],
+}
+
+impl Transposition {
+    pub(crate) fn set_semitones(&self, speaker: Speaker, semitones: i8) {
+        self.semitones[speaker.i()].store(semitones);
+    }
+
+    pub(crate) fn set_octaves(&self, speaker: Speaker, octaves: i8) {
+        self.octaves[speaker.i()].store(octaves);
+    }
+
+    /// Returns the total number of semitones by which the notes of a part for `speaker` are transposed.
+    fn offset(&self, speaker: Speaker) -> i32 {
+        let shift = |i: usize| self.semitones[i].load() as i32 + 12 * self.octaves[i].load() as i32;
+        match speaker {
+            Speaker::Both => shift(Speaker::Both.i()),
+            Speaker::Left | Speaker::Right => shift(speaker.i()) + shift(Speaker::Both.i()),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MonoPlayer<const N: usize> {
+    voices: Voices,
+    next: ModNumC<usize, N>,
+    /// The voice playing each key, indexed by the key as received, before transposition.
+    pitch2state: [Option<usize>; NUM_MIDI_VALUES],
+    /// The transposed pitch sounding from each voice.
+    recent_pitches: [Option<u8>; N],
+    /// The key, before transposition, that started each voice, so that `Note Off` releases the right
+    /// voice even if the transposition has since changed.
+    recent_keys: [Option<u8>; N],
+    zone: Zone,
+    transposition: Arc<Transposition>,
+    synth_func: SynthFunc,
+    program_table: Arc<SharedProgramTable>,
+    adaptive: Option<AdaptiveJust>,
+    velocity_curve: VelocityCurve,
+    changes: Vec<VoiceChange>,
+}
+
+impl<const N: usize> MonoPlayer<N> {
+    fn new(
+        program_table: Arc<SharedProgramTable>,
+        zone: Zone,
+        transposition: Arc<Transposition>,
+    ) -> anyhow::Result<Self> {
+        let Some(synth_func) = program_table.get(zone.program as usize) else {
+            bail!("Program {} is not in the program table", zone.program);
+        };
+        let keys = Arc::new(KeyTable::new(&midi_hz::<f32>));
+        let states = (0..N)
+            .map(|_| {
+                let mut state = SharedMidiState::default();
+                state.set_tuning(keys.clone());
+                state
+            })
+            .collect();
+        Ok(Self {
+            voices: Voices {
+                states,
+                master_volume: shared(1.0),
+                keys,
+            },
+            next: ModNumC::new(0),
+            pitch2state: [None; NUM_MIDI_VALUES],
+            recent_pitches: [None; N],
+            recent_keys: [None; N],
+            zone,
+            transposition,
+            synth_func,
+            program_table,
+            adaptive: None,
+            velocity_curve: VelocityCurve::default(),
+            changes: vec![],
+        })
+    }
+
+    fn set_tuning(&self, tuning: &dyn Tuning) {
+        self.voices.keys.retune_all(tuning);
+    }
+
+    fn sound(&self) -> Net {
+        let mut sound = Net::wrap(self.sound_at(0));
+        for i in 1..N {
+            sound = Net::binary(sound, Net::wrap(self.sound_at(i)), FrameAdd::new());
+        }
+        Net::binary(
+            sound,
+            Net::wrap(Box::new(var(&self.voices.master_volume))),
+            FrameMul::new(),
+        )
+    }
+
+    fn decode(&mut self, msg: &MidiMsg) -> Option<RelayedMessage> {
+        match msg {
+            MidiMsg::ChannelVoice { channel: _, msg } => match msg {
+                ChannelVoiceMsg::NoteOn { note, velocity } => {
+                    if *velocity == 0_u8 {
+                        self.off(*note);
+                    } else {
+                        self.on(*note, *velocity);
+                    }
+                }
+                ChannelVoiceMsg::NoteOff { note, velocity: _ } => {
+                    self.off(*note);
+                }
+                ChannelVoiceMsg::PitchBend { bend } => {
+                    self.bend(*bend);
+                }
+                ChannelVoiceMsg::ProgramChange { program } => {
+                    if let Some(new_synth) = self.program_table.get(*program as usize) {
+                        self.change_synth(new_synth);
+                        return Some(RelayedMessage::SynthChange);
+                    }
+                }
+                _ => {}
+            },
+            MidiMsg::ChannelMode { channel: _, msg } => match msg {
+                ChannelModeMsg::AllNotesOff => self.release_all(),
+                ChannelModeMsg::AllSoundOff => self.all_sounds_off(),
+                _ => {}
+            },
+            MidiMsg::SystemRealTime { msg } => match msg {
+                SystemRealTimeMsg::SystemReset => return Some(RelayedMessage::SystemReset),
+                _ => {}
+            },
+            MidiMsg::SystemExclusive { .. } => {
+                if let Some((keys, real_time)) = retuned_keys(msg) {
+                    self.retune(keys, real_time);
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn find_next_state(&mut self) -> usize {
+        for i in self.next.iter() {
+            if self.recent_pitches[i.a()].is_none() {
+                return self.claim_state(i);
+            }
+        }
+        self.pitch2state[self.recent_keys[self.next.a()].unwrap() as usize] = None;
+        self.release(self.next.a());
+        self.claim_state(self.next)
+    }
+
+    fn claim_state(&mut self, state: ModNumC<usize, N>) -> usize {
+        let next = state.a();
+        self.next = state + 1;
+        next
+    }
+
+    fn on(&mut self, key: u8, velocity: u8) {
+        let offset = self.zone.transpose as i32 + self.transposition.offset(self.zone.speaker);
+        let Ok(pitch) = u8::try_from(key as i32 + offset) else {
+            return;
+        };
+        if pitch as usize >= NUM_MIDI_VALUES || !self.voices.keys.plays(pitch) {
+            return;
+        }
+        self.changes.push(VoiceChange::MasterVolume(1.0));
+        let selected = self.find_next_state();
+        let volume = self
+            .zone
+            .velocity_curve
+            .as_ref()
+            .unwrap_or(&self.velocity_curve)
+            .volume(velocity);
+        self.changes.push(VoiceChange::On {
+            voice: selected,
+            pitch,
+            volume,
+        });
+        self.pitch2state[key as usize] = Some(selected);
+        self.recent_pitches[selected] = Some(pitch);
+        self.recent_keys[selected] = Some(key);
+        self.adapt(Some(selected));
+    }
+
+    fn off(&mut self, key: u8) {
+        if let Some(i) = self.pitch2state[key as usize] {
+            if self.recent_keys[i] == Some(key) {
+                self.release(i);
+                self.adapt(None);
+            }
+            self.pitch2state[key as usize] = None;
+        }
+    }
+
+    fn change_synth(&mut self, new_synth: SynthFunc) {
+        self.all_sounds_off();
+        self.synth_func = new_synth;
+    }
+
+    fn bend(&mut self, bend: u16) {
+        self.changes.push(VoiceChange::Bend(bend));
+    }
+
+    fn sound_at(&self, i: usize) -> Box<dyn AudioUnit> {
+        (self.synth_func)(&self.voices.states[i])
+    }
+
+    fn release(&mut self, i: usize) {
+        self.recent_pitches[i] = None;
+        self.recent_keys[i] = None;
+        self.changes.push(VoiceChange::Off { voice: i });
+    }
+
+    fn release_all(&mut self) {
+        for i in 0..N {
+            self.release(i);
+        }
+    }
+
+    fn retune(&mut self, keys: Vec<(u8, f32)>, real_time: bool) {
+        for (key, hz) in keys {
+            self.changes.push(VoiceChange::Tune { key, hz });
+            if real_time {
+                for voice in 0..N {
+                    if self.recent_pitches[voice] == Some(key) {
+                        self.changes.push(VoiceChange::Retune { voice, pitch: key });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Retunes the held notes by `adaptive`, if in use. The note just played by voice `started`
+    /// starts at its new frequency rather than gliding to it.
+    fn adapt(&mut self, started: Option<usize>) {
+        let Some(adaptive) = self.adaptive else {
+            return;
+        };
+        let (voices, pitches): (Vec<usize>, Vec<u8>) = self
+            .recent_pitches
+            .iter()
+            .enumerate()
+            .filter_map(|(voice, pitch)| pitch.map(|pitch| (voice, pitch)))
+            .unzip();
+        let frequencies = adaptive.frequencies(&pitches, self.voices.keys.as_ref());
+        for (voice, hz) in voices.into_iter().zip(frequencies) {
+            let duration = if started == Some(voice) {
+                Duration::ZERO
+            } else {
+                adaptive.glide
+            };
+            self.changes.push(VoiceChange::Glide {
+                voice,
+                hz,
+                duration,
+            });
+        }
+    }
+
+    fn all_sounds_off(&mut self) {
+        self.changes.push(VoiceChange::MasterVolume(0.0));
+    }
+}
+
+/// Routes MIDI messages to a `MonoPlayer` for each `Zone`, mixing their sounds into stereo.
+/// By default, there are two zones, one for each `Speaker`.
+pub(crate) struct StereoPlayer<const N: usize> {
+    sounds: Vec<MonoPlayer<N>>,
+    transposition: Arc<Transposition>,
+    clock: Arc<MidiClock>,
+    events: Arc<SegQueue<VoiceEvent>>,
+    deferred: Arc<SegQueue<VoiceEvent>>,
+}
+
+impl<const N: usize> StereoPlayer<N> {
+    pub(crate) fn new(program_table: Arc<SharedProgramTable>) -> Self {
+        let zones = [Speaker::Left, Speaker::Right]
+            .map(|speaker| Zone::new(0).with_speaker(speaker))
+            .to_vec();
+        Self::with_zones(program_table, zones)
+            .expect("program table must contain at least one program")
+    }
+
+    pub(crate) fn with_zones(
+        program_table: Arc<SharedProgramTable>,
+        zones: Vec<Zone>,
+    ) -> anyhow::Result<Self> {
+        if zones.is_empty() {
+            bail!("A synthesizer must have at least one zone");
+        }
+        let transposition = Arc::new(Transposition::default());
+        let sounds = zones
+            .into_iter()
+            .map(|zone| MonoPlayer::<N>::new(program_table.clone(), zone, transposition.clone()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            sounds,
+            transposition,
+            clock: Arc::new(MidiClock::default()),
+            events: Arc::new(SegQueue::new()),
+            deferred: Arc::new(SegQueue::new()),
+        })
+    }
+
+    pub(crate) fn set_tuning(&self, tuning: &dyn Tuning) {
+        for sound in self.sounds.iter() {
+            sound.set_tuning(tuning);
+        }
+    }
+
+    pub(crate) fn set_adaptive_tuning(&mut self, adaptive: Option<AdaptiveJust>) {
+        for sound in self.sounds.iter_mut() {
+            sound.adaptive = adaptive;
+        }
+    }
+
+    pub(crate) fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        for sound in self.sounds.iter_mut() {
+            sound.velocity_curve = curve.clone();
+        }
+    }
+
+    /// Returns the `Transposition` of the notes of each `MonoPlayer`, through which other threads may change it.
+    pub(crate) fn transposition(&self) -> Arc<Transposition> {
+        self.transposition.clone()
+    }
+
+    /// Returns the `MidiClock` following the clock messages received, which other threads may read.
+    pub(crate) fn clock(&self) -> Arc<MidiClock> {
+        self.clock.clone()
+    }
+
+    /// Returns the `KeyTable` tuning the notes of each `MonoPlayer`, through which other threads may retune them.
+    pub(crate) fn key_tables(&self) -> Vec<Arc<KeyTable>> {
+        self.sounds
+            .iter()
+            .map(|sound| sound.voices.keys.clone())
+            .collect()
+    }
+
+    fn sound(&self) -> Net {
+        self.sounds
+            .iter()
+            .map(|sound| {
+                let (left, right) = sound.zone.gains();
+                Net::pipe(
+                    sound.sound(),
+                    Net::wrap(Box::new((pass() * left) ^ (pass() * right))),
+                )
+            })
+            .reduce(|mix, sound| Net::binary(mix, sound, FrameAdd::new()))
+            .expect("a synthesizer has at least one zone")
+    }
+
+    pub(crate) fn run_output(
+        &mut self,
+        midi_msgs: Arc<MsgQueue<SynthMsg>>,
+        sink: &mut dyn AudioSink,
+        stop: &AtomicCell<bool>,
+    ) -> anyhow::Result<()> {
+        Self::warm_up(midi_msgs.clone());
+        loop {
+            sink.play(self.renderer(sink.sample_rate()))?;
+            if self.handle_messages(midi_msgs.clone(), stop) == RelayedMessage::SystemReset {
+                return Ok(());
+            }
+        }
+    }
+
+    fn decode(&mut self, speaker: Speaker, msg: &MidiMsg, time: Instant) -> Option<RelayedMessage> {
+        self.clock.handle(msg, time);
+        let mut result = None;
+        for (part, sound) in self.sounds.iter_mut().enumerate() {
+            if !sound.zone.accepts(speaker, msg) {
+                continue;
+            }
+            result = result.or(sound.decode(msg));
+            for change in sound.changes.drain(..) {
+                self.events.push(VoiceEvent { time, part, change });
+            }
+        }
+        result
+    }
+
+    fn scheduler(&self, sample_rate: f64) -> EventScheduler {
+        EventScheduler {
+            events: self.events.clone(),
+            deferred: self.deferred.clone(),
+            voices: self
+                .sounds
+                .iter()
+                .map(|sound| sound.voices.clone())
+                .collect(),
+            glides: Vec::with_capacity(self.sounds.len() * N),
+            pending: None,
+            frame_period: Duration::from_secs_f64(1.0 / sample_rate),
+            buffer_start: Instant::now(),
+        }
+    }
+
+    fn warm_up(midi_msgs: Arc<MsgQueue<SynthMsg>>) {
+        for _ in 0..N {
+            midi_msgs.push(Self::warm_up_msg(ChannelVoiceMsg::NoteOn {
+                note: 0,
+                velocity: 0,
+            }));
+            midi_msgs.push(Self::warm_up_msg(ChannelVoiceMsg::NoteOff {
+                note: 0,
+                velocity: 0,
+            }));
+        }
+    }
+
+    fn warm_up_msg(msg: ChannelVoiceMsg) -> SynthMsg {
+        SynthMsg::new(
+            MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg,
+            },
+            Speaker::Both,
+        )
+    }
+
+    /// Returns `RelayedMessage::SystemReset` once `true` is stored in `stop`.
+    fn handle_messages(
+        &mut self,
+        midi_msgs: Arc<MsgQueue<SynthMsg>>,
+        stop: &AtomicCell<bool>,
+    ) -> RelayedMessage {
+        loop {
+            let Some(msg) = midi_msgs.pop_wait_unless(stop) else {
+                return RelayedMessage::SystemReset;
+            };
+            let time = msg.time.unwrap_or_else(Instant::now);
+            if let Some(relayed) = self.decode(msg.speaker, &msg.msg, time) {
+                return relayed;
+            }
+        }
+    }
+
+    fn renderer(&self, sample_rate: f64) -> Renderer {
+        let mut sound = self.sound();
+        sound.reset();
+        sound.set_sample_rate(sample_rate);
+        Renderer {
+            sound,
+            scheduler: self.scheduler(sample_rate),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use float_eq::assert_float_eq;
+    use fundsp::math::midi_hz;
+    use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg};
+
+    use crate::{
+        CONTROL_OFF, CONTROL_ON,
+        io::Speaker,
+        sound_builders::SharedProgramTable,
+        sounds::options,
+        synth::{EventScheduler, StereoPlayer, Synth},
+        tunings::{FIVE_LIMIT, RatioTuning, adaptive::AdaptiveJust},
+        zones::Zone,
+    };
+
+    #[test]
+    fn test_events_applied_at_frame() {
+        let mut player = StereoPlayer::<4>::new(Arc::new(SharedProgramTable::new(options())));
+        let mut scheduler = player.scheduler(1000.0);
+        scheduler.buffer_start = Instant::now();
+        let note_on = MidiMsg::ChannelVoice {
+            channel: Channel::Ch1,
+            msg: ChannelVoiceMsg::NoteOn {
+                note: 60,
+                velocity: 100,
+            },
+        };
+        player.decode(
+            Speaker::Left,
+            &note_on,
+            scheduler.buffer_start + Duration::from_millis(10),
+        );
+        let control = |scheduler: &EventScheduler| {
+            scheduler.voices[Speaker::Left.i()].states[0]
+                .control_var()
+                .value()
+        };
+        scheduler.apply_events(9);
+        assert_eq!(control(&scheduler), CONTROL_OFF);
+        scheduler.apply_events(10);
+        assert_eq!(control(&scheduler), CONTROL_ON);
+        assert_eq!(
+            scheduler.voices[Speaker::Right.i()].states[0]
+                .control_var()
+                .value(),
+            CONTROL_OFF
+        );
+    }
+
+    #[test]
+    fn test_synth_renders_at_frame() {
+        let mut synth = Synth::<4>::new(Arc::new(SharedProgramTable::new(options())), 44100.0);
+        let mut left = [1.0; 512];
+        let mut right = [1.0; 512];
+        synth.handle_at(
+            &MidiMsg::ChannelVoice {
+                channel: Channel::Ch1,
+                msg: ChannelVoiceMsg::NoteOn {
+                    note: 60,
+                    velocity: 127,
+                },
+            },
+            Speaker::Left,
+            256,
+        );
+        synth.process(&mut left, &mut right);
+        assert!(left[..256].iter().all(|s| *s == 0.0));
+        assert!(left[256..].iter().any(|s| *s != 0.0));
+        assert!(right.iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn test_set_tuning_retunes_keys() {
+        let player = StereoPlayer::<4>::new(Arc::new(SharedProgramTable::new(options())));
+        player.set_tuning(&RatioTuning::new(FIVE_LIMIT, 69, 440.0));
+        for keys in player.key_tables() {
+            assert_eq!(keys.get(69), 440.0);
+            assert_eq!(keys.get(76), 660.0);
+            assert_eq!(keys.get(57), 220.0);
+        }
+    }
+
+    #[test]
+    fn test_adaptive_tuning_glides() {
+        let mut player = StereoPlayer::<4>::new(Arc::new(SharedProgramTable::new(options())));
+        player.set_adaptive_tuning(Some(
+            AdaptiveJust::new(FIVE_LIMIT).with_glide(Duration::from_millis(10)),
+        ));
+        let mut scheduler = player.scheduler(1000.0);
+        scheduler.buffer_start = Instant::now();
+        for note in [66, 62] {
+            player.decode(
+                Speaker::Both,
+                &MidiMsg::ChannelVoice {
+                    channel: Channel::Ch1,
+                    msg: ChannelVoiceMsg::NoteOn {
+                        note,
+                        velocity: 100,
+                    },
+                },
+                scheduler.buffer_start,
+            );
+        }
+        let hz = |scheduler: &EventScheduler, voice: usize| {
+            scheduler.voices[Speaker::Left.i()].states[voice].hz()
+        };
+        let d = midi_hz(62.0);
+        scheduler.apply_events(0);
+        assert_float_eq!(hz(&scheduler, 1), d, rmax <= 1e-5);
+        assert!(hz(&scheduler, 0) > d * 5.0 / 4.0);
+        for frame in 1..10 {
+            scheduler.apply_events(frame);
+        }
+        assert_float_eq!(hz(&scheduler, 0), d * 5.0 / 4.0, rmax <= 1e-5);
+    }
+
+    #[test]
+    fn test_transpose_mid_note() {
+        let mut player = StereoPlayer::<4>::new(Arc::new(SharedProgramTable::new(options())));
+        let mut scheduler = player.scheduler(1000.0);
+        scheduler.buffer_start = Instant::now();
+        let transposition = player.transposition();
+        transposition.set_semitones(Speaker::Left, 2);
+        transposition.set_octaves(Speaker::Both, 1);
+        let note = |msg| MidiMsg::ChannelVoice {
+            channel: Channel::Ch1,
+            msg,
+        };
+        player.decode(
+            Speaker::Both,
+            &note(ChannelVoiceMsg::NoteOn {
+                note: 60,
+                velocity: 100,
+            }),
+            scheduler.buffer_start,
+        );
+        scheduler.apply_events(0);
+        let state = |scheduler: &EventScheduler, speaker: Speaker| {
+            scheduler.voices[speaker.i()].states[0].clone()
+        };
+        assert_eq!(state(&scheduler, Speaker::Left).hz(), midi_hz(74.0));
+        assert_eq!(state(&scheduler, Speaker::Right).hz(), midi_hz(72.0));
+
+        transposition.set_octaves(Speaker::Both, 0);
+        player.decode(
+            Speaker::Both,
+            &note(ChannelVoiceMsg::NoteOff {
+                note: 60,
+                velocity: 0,
+            }),
+            scheduler.buffer_start,
+        );
+        scheduler.apply_events(1);
+        for speaker in [Speaker::Left, Speaker::Right] {
+            assert_eq!(
+                state(&scheduler, speaker).control_var().value(),
+                CONTROL_OFF
+            );
+        }
+    }
+
+    #[test]
+    fn test_zones_split_and_layer() {
+        let zones = vec![
+            Zone::new(0).with_keys(0..=59).with_speaker(Speaker::Left),
+            Zone::new(1)
+                .with_keys(60..=127)
+                .with_speaker(Speaker::Right),
+            Zone::new(2)
+                .with_keys(60..=127)
+                .with_velocities(1..=63)
+                .with_transpose(12),
+        ];
+        let mut player =
+            StereoPlayer::<4>::with_zones(Arc::new(SharedProgramTable::new(options())), zones)
+                .unwrap();
+        let mut scheduler = player.scheduler(1000.0);
+        scheduler.buffer_start = Instant::now();
+        let mut play = |msg, frame| {
+            player.decode(
+                Speaker::Both,
+                &MidiMsg::ChannelVoice {
+                    channel: Channel::Ch1,
+                    msg,
+                },
+                scheduler.buffer_start,
+            );
+            scheduler.apply_events(frame);
+            scheduler
+                .voices
+                .iter()
+                .map(|voices| voices.states[0].clone())
+                .collect::<Vec<_>>()
+        };
+        let states = play(
+            ChannelVoiceMsg::NoteOn {
+                note: 40,
+                velocity: 100,
+            },
+            0,
+        );
+        assert_eq!(states[0].control_var().value(), CONTROL_ON);
+        assert_eq!(states[1].control_var().value(), CONTROL_OFF);
+        assert_eq!(states[2].control_var().value(), CONTROL_OFF);
+
+        let states = play(
+            ChannelVoiceMsg::NoteOn {
+                note: 70,
+                velocity: 30,
+            },
+            1,
+        );
+        assert_eq!(states[1].hz(), midi_hz(70.0));
+        assert_eq!(states[2].hz(), midi_hz(82.0));
+        assert_eq!(states[2].control_var().value(), CONTROL_ON);
+
+        let states = play(
+            ChannelVoiceMsg::NoteOff {
+                note: 70,
+                velocity: 90,
+            },
+            2,
+        );
+        assert_eq!(states[0].control_var().value(), CONTROL_ON);
+        assert_eq!(states[1].control_var().value(), CONTROL_OFF);
+        assert_eq!(states[2].control_var().value(), CONTROL_OFF);
+        assert!(
+            StereoPlayer::<4>::with_zones(
+                Arc::new(SharedProgramTable::new(options())),
+                vec![Zone::new(u8::MAX)]
+            )
+            .is_err()
+        );
+    }
+}
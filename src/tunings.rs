@@ -1,7 +1,333 @@
+use crossbeam_utils::atomic::AtomicCell;
+use num_rational::Ratio;
+
+use crate::NUM_MIDI_VALUES;
+
+pub mod adaptive;
+pub mod mts;
+pub mod scala;
+
+/// Converts MIDI pitches (0-127) to frequencies in Hz.
+///
+/// Every `fn(f32) -> f32`, such as `well_temperament` or `fundsp::math::midi_hz`, is a `Tuning`.
+/// Types that carry data, such as `scala::ScalaTuning`, can implement it as well.
+pub trait Tuning: Send + Sync {
+    /// Returns the frequency in Hz of `midi_pitch`.
+    fn midi_to_hz(&self, midi_pitch: f32) -> f32;
+
+    /// Returns `false` if `key` is to be left silent, as are keys that a Scala keyboard mapping marks `x`.
+    /// Players ignore `Note On` messages for such keys. By default, every key plays.
+    fn plays(&self, _key: u8) -> bool {
+        true
+    }
+}
+
+impl<F: Fn(f32) -> f32 + Send + Sync> Tuning for F {
+    fn midi_to_hz(&self, midi_pitch: f32) -> f32 {
+        self(midi_pitch)
+    }
+}
+
+/// A frequency for each MIDI key, any of which may be changed while notes are playing.
+///
+/// Players tune their notes with a `KeyTable`, so that `mts` messages can retune individual keys.
+pub struct KeyTable {
+    hz: Vec<AtomicCell<f32>>,
+    played: Vec<AtomicCell<bool>>,
+}
+
+impl KeyTable {
+    /// Creates a table assigning each key its frequency in `tuning`.
+    pub fn new(tuning: &dyn Tuning) -> Self {
+        Self {
+            hz: (0..NUM_MIDI_VALUES)
+                .map(|key| AtomicCell::new(tuning.midi_to_hz(key as f32)))
+                .collect(),
+            played: (0..NUM_MIDI_VALUES)
+                .map(|key| AtomicCell::new(tuning.plays(key as u8)))
+                .collect(),
+        }
+    }
+
+    /// Assigns each key its frequency in `tuning`.
+    pub fn retune_all(&self, tuning: &dyn Tuning) {
+        for (key, (hz, played)) in self.hz.iter().zip(self.played.iter()).enumerate() {
+            hz.store(tuning.midi_to_hz(key as f32));
+            played.store(tuning.plays(key as u8));
+        }
+    }
+
+    /// Assigns `hz` to `key`, which plays even if its tuning left it silent.
+    pub fn set(&self, key: u8, hz: f32) {
+        self.hz[key as usize].store(hz);
+        self.played[key as usize].store(true);
+    }
+
+    /// Returns the frequency assigned to `key`.
+    pub fn get(&self, key: u8) -> f32 {
+        self.hz[key as usize].load()
+    }
+}
+
+impl Tuning for KeyTable {
+    fn midi_to_hz(&self, midi_pitch: f32) -> f32 {
+        self.get((midi_pitch as usize).min(NUM_MIDI_VALUES - 1) as u8)
+    }
+
+    fn plays(&self, key: u8) -> bool {
+        self.played
+            .get(key as usize)
+            .is_some_and(|played| played.load())
+    }
+}
+
+/// 5-limit just intonation: the ratio to the tonic of each of the twelve pitches in an octave.
+pub const FIVE_LIMIT: [Ratio<u16>; 12] = [
+    Ratio::new_raw(1, 1),
+    Ratio::new_raw(16, 15),
+    Ratio::new_raw(9, 8),
+    Ratio::new_raw(6, 5),
+    Ratio::new_raw(5, 4),
+    Ratio::new_raw(4, 3),
+    Ratio::new_raw(45, 32),
+    Ratio::new_raw(3, 2),
+    Ratio::new_raw(8, 5),
+    Ratio::new_raw(5, 3),
+    Ratio::new_raw(9, 5),
+    Ratio::new_raw(15, 8),
+];
+
+/// 7-limit just intonation: the ratio to the tonic of each of the twelve pitches in an octave.
+/// Uses the harmonic seventh (7/4) and septimal intervals in place of several 5-limit ratios.
+pub const SEVEN_LIMIT: [Ratio<u16>; 12] = [
+    Ratio::new_raw(1, 1),
+    Ratio::new_raw(15, 14),
+    Ratio::new_raw(8, 7),
+    Ratio::new_raw(6, 5),
+    Ratio::new_raw(5, 4),
+    Ratio::new_raw(4, 3),
+    Ratio::new_raw(7, 5),
+    Ratio::new_raw(3, 2),
+    Ratio::new_raw(8, 5),
+    Ratio::new_raw(5, 3),
+    Ratio::new_raw(7, 4),
+    Ratio::new_raw(15, 8),
+];
+
+/// MIDI pitch of A above middle C, the customary reference pitch.
+const A4: u8 = 69;
+
+/// 5-limit just intonation with MIDI pitch `M` as its tonic. The tonic is tuned as in equal
+/// temperament with A above middle C at `P` Hz; every other pitch is tuned by its `FIVE_LIMIT`
+/// ratio to the nearest tonic below it.
+///
+/// For example, `just_intonation::<60, 440>` is just intonation in C with standard concert pitch.
 pub fn just_intonation<const M: u8, const P: u16>(midi_pitch: f32) -> f32 {
-    let midi_pitch = midi_pitch as u8;
+    ratio_tuning(&FIVE_LIMIT, M, P as f32, midi_pitch)
+}
+
+/// Behaves like `just_intonation()`, but tunes with the `SEVEN_LIMIT` ratios.
+pub fn just_intonation_7_limit<const M: u8, const P: u16>(midi_pitch: f32) -> f32 {
+    ratio_tuning(&SEVEN_LIMIT, M, P as f32, midi_pitch)
+}
+
+/// A tuning by ratios to a tonic, like `ratio_tuning()`, whose ratios, tonic, and reference pitch
+/// can be chosen while a program runs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RatioTuning {
+    /// The ratio to the tonic of each of the twelve pitches in an octave, such as `FIVE_LIMIT`.
+    pub ratios: [Ratio<u16>; 12],
+    /// MIDI pitch of the tonic.
+    pub tonic: u8,
+    /// Frequency in Hz of A above middle C in the equal temperament from which the tonic is tuned.
+    pub reference: f32,
+}
+
+impl RatioTuning {
+    /// Creates a tuning by `ratios` to the MIDI pitch `tonic`, with A above middle C at `reference` Hz.
+    pub fn new(ratios: [Ratio<u16>; 12], tonic: u8, reference: f32) -> Self {
+        Self {
+            ratios,
+            tonic,
+            reference,
+        }
+    }
+}
+
+impl Tuning for RatioTuning {
+    fn midi_to_hz(&self, midi_pitch: f32) -> f32 {
+        ratio_tuning(&self.ratios, self.tonic, self.reference, midi_pitch)
+    }
+}
+
+/// Tunes `midi_pitch` by its ratio in `ratios` to the nearest MIDI pitch `tonic` below it, in any octave.
+/// The `tonic` is tuned as in equal temperament with A above middle C at `reference` Hz.
+pub fn ratio_tuning(ratios: &[Ratio<u16>; 12], tonic: u8, reference: f32, midi_pitch: f32) -> f32 {
+    let semitones = midi_pitch as i32 - tonic as i32;
+    let octave = semitones.div_euclid(12);
+    let ratio = ratios[semitones.rem_euclid(12) as usize];
+    let tonic_hz = reference * 2.0_f32.powf((tonic as f32 - A4 as f32) / 12.0);
+    tonic_hz * 2.0_f32.powi(octave) * *ratio.numer() as f32 / *ratio.denom() as f32
+}
+
+/// Divides an interval, such as the octave, into equal steps, one per MIDI pitch.
+///
+/// Pitches need not repeat at the octave: `bohlen_pierce()` divides the tritave (3:1) instead, and
+/// `from_step_cents()` builds scales, such as Wendy Carlos's, from a step size alone.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EqualTemperament {
+    /// Number of steps into which `interval` is divided.
+    pub divisions: f32,
+    /// The ratio divided into `divisions` equal steps.
+    pub interval: f32,
+    /// MIDI pitch tuned to `anchor_hz`.
+    pub anchor: u8,
+    /// Frequency in Hz of `anchor`.
+    pub anchor_hz: f32,
+}
+
+impl EqualTemperament {
+    /// Divides `interval` into `divisions` equal steps, with MIDI pitch `anchor` at `anchor_hz` Hz.
+    pub fn new(divisions: f32, interval: f32, anchor: u8, anchor_hz: f32) -> Self {
+        Self {
+            divisions,
+            interval,
+            anchor,
+            anchor_hz,
+        }
+    }
+
+    /// Divides the octave into `divisions` equal steps, such as 19, 22, 31, or 53.
+    pub fn edo(divisions: u16, anchor: u8, anchor_hz: f32) -> Self {
+        Self::new(divisions as f32, 2.0, anchor, anchor_hz)
+    }
+
+    /// The Bohlen-Pierce scale, dividing the tritave (3:1) into 13 equal steps.
+    pub fn bohlen_pierce(anchor: u8, anchor_hz: f32) -> Self {
+        Self::new(13.0, 3.0, anchor, anchor_hz)
+    }
+
+    /// Steps of `cents` each, regardless of whether they ever reach an octave.
+    /// For example, Wendy Carlos's alpha scale has steps of 78 cents.
+    pub fn from_step_cents(cents: f32, anchor: u8, anchor_hz: f32) -> Self {
+        Self::new(1200.0 / cents, 2.0, anchor, anchor_hz)
+    }
+}
+
+impl Tuning for EqualTemperament {
+    fn midi_to_hz(&self, midi_pitch: f32) -> f32 {
+        self.anchor_hz
+            * self
+                .interval
+                .powf((midi_pitch - self.anchor as f32) / self.divisions)
+    }
+}
 
-    todo!("Write the rest of this: {midi_pitch}")
+/// Divides the octave into `D` equal steps, with MIDI pitch `M` at `P` Hz.
+///
+/// For example, `edo::<31, 69, 440>` is 31-EDO with A above middle C at standard concert pitch.
+pub fn edo<const D: u16, const M: u8, const P: u16>(midi_pitch: f32) -> f32 {
+    EqualTemperament::edo(D, M, P as f32).midi_to_hz(midi_pitch)
+}
+
+/// The Bohlen-Pierce scale, with MIDI pitch `M` at `P` Hz.
+pub fn bohlen_pierce<const M: u8, const P: u16>(midi_pitch: f32) -> f32 {
+    EqualTemperament::bohlen_pierce(M, P as f32).midi_to_hz(midi_pitch)
+}
+
+/// Pythagorean tuning: eleven pure fifths from E flat to G sharp, leaving a wolf fifth from G sharp to E flat.
+/// Cents above the root of each of the twelve pitches in an octave.
+pub const PYTHAGOREAN: [f32; 12] = [
+    0.0, 113.685, 203.910, 294.135, 407.820, 498.045, 611.730, 701.955, 815.640, 905.865, 996.090,
+    1109.775,
+];
+
+/// Quarter-comma meantone: fifths narrowed by a quarter of a syntonic comma, yielding pure major thirds,
+/// from E flat to G sharp. Cents above the root of each of the twelve pitches in an octave.
+pub const QUARTER_COMMA_MEANTONE: [f32; 12] = [
+    0.0, 76.049, 193.157, 310.265, 386.314, 503.422, 579.471, 696.579, 772.627, 889.735, 1006.843,
+    1082.892,
+];
+
+/// Werckmeister III: the fifths C-G-D-A and B-F# narrowed by a quarter of a Pythagorean comma, and all
+/// others pure. Cents above the root of each of the twelve pitches in an octave.
+pub const WERCKMEISTER_III: [f32; 12] = [
+    0.0, 90.225, 192.180, 294.135, 390.225, 498.045, 588.270, 696.090, 792.180, 888.270, 996.090,
+    1092.18,
+];
+
+/// Kirnberger III: the fifths C-G-D-A-E narrowed by a quarter of a syntonic comma, F#-C# narrowed by a
+/// schisma, and all others pure. Cents above the root of each of the twelve pitches in an octave.
+pub const KIRNBERGER_III: [f32; 12] = [
+    0.0, 90.225, 193.157, 294.135, 386.314, 498.045, 590.224, 696.579, 792.180, 889.735, 996.090,
+    1088.269,
+];
+
+/// Vallotti: the fifths F-C-G-D-A-E-B narrowed by a sixth of a Pythagorean comma, and all others pure.
+/// Cents above the root of each of the twelve pitches in an octave.
+pub const VALLOTTI: [f32; 12] = [
+    0.0, 94.135, 196.090, 298.045, 392.180, 501.955, 592.180, 698.045, 796.090, 894.135, 1000.000,
+    1090.225,
+];
+
+/// A twelve-note temperament, transposed to any root and tuned to any concert pitch.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Temperament {
+    /// Cents above the root of each of the twelve pitches in an octave, such as `WERCKMEISTER_III`.
+    pub cents: [f32; 12],
+    /// Pitch class of the root, from 0 (C) to 11 (B).
+    pub root: u8,
+    /// Frequency in Hz of A above middle C, such as 415 for many baroque ensembles.
+    pub concert_a: f32,
+}
+
+impl Temperament {
+    /// Tunes the pitches of `cents` above pitch class `root`, with A above middle C at `concert_a` Hz.
+    pub fn new(cents: [f32; 12], root: u8, concert_a: f32) -> Self {
+        Self {
+            cents,
+            root: root % 12,
+            concert_a,
+        }
+    }
+
+    /// Pythagorean tuning rooted on pitch class `root`.
+    pub fn pythagorean(root: u8, concert_a: f32) -> Self {
+        Self::new(PYTHAGOREAN, root, concert_a)
+    }
+
+    /// Quarter-comma meantone rooted on pitch class `root`.
+    pub fn quarter_comma_meantone(root: u8, concert_a: f32) -> Self {
+        Self::new(QUARTER_COMMA_MEANTONE, root, concert_a)
+    }
+
+    /// Werckmeister III rooted on pitch class `root`.
+    pub fn werckmeister_iii(root: u8, concert_a: f32) -> Self {
+        Self::new(WERCKMEISTER_III, root, concert_a)
+    }
+
+    /// Kirnberger III rooted on pitch class `root`.
+    pub fn kirnberger_iii(root: u8, concert_a: f32) -> Self {
+        Self::new(KIRNBERGER_III, root, concert_a)
+    }
+
+    /// Vallotti rooted on pitch class `root`.
+    pub fn vallotti(root: u8, concert_a: f32) -> Self {
+        Self::new(VALLOTTI, root, concert_a)
+    }
+
+    /// Returns the number of cents by which `midi_pitch` lies above the root in MIDI octave -1.
+    fn cents_above_root(&self, midi_pitch: i32) -> f32 {
+        let semitones = midi_pitch - self.root as i32;
+        1200.0 * semitones.div_euclid(12) as f32 + self.cents[semitones.rem_euclid(12) as usize]
+    }
+}
+
+impl Tuning for Temperament {
+    fn midi_to_hz(&self, midi_pitch: f32) -> f32 {
+        let cents = self.cents_above_root(midi_pitch as i32) - self.cents_above_root(A4 as i32);
+        self.concert_a * 2.0_f32.powf(cents / 1200.0)
+    }
 }
 
 const WELL_C_MINUS_1: f32 = 8.20354352009375;
@@ -32,8 +358,12 @@ pub fn well_temperament(midi_pitch: f32) -> f32 {
 
 #[cfg(test)]
 mod tests {
-    use crate::tunings::well_temperament;
+    use crate::tunings::{
+        EqualTemperament, Temperament, Tuning, bohlen_pierce, edo, just_intonation,
+        just_intonation_7_limit, well_temperament,
+    };
     use float_eq::assert_float_eq;
+    use fundsp::math::midi_hz;
 
     #[test]
     fn test_well() {
@@ -55,4 +385,97 @@ mod tests {
             assert_float_eq!(well_temperament(midi), hz, abs <= 1e-3);
         }
     }
+
+    #[test]
+    fn test_just() {
+        // Just intonation in C, from middle C tuned in equal temperament at 261.626 Hz.
+        for (midi, hz) in [
+            (48.0, 130.813),
+            (59.0, 245.274),
+            (60.0, 261.626),
+            (62.0, 294.329),
+            (64.0, 327.032),
+            (65.0, 348.834),
+            (67.0, 392.438),
+            (69.0, 436.043),
+            (70.0, 470.926),
+            (71.0, 490.548),
+            (72.0, 523.251),
+        ] {
+            assert_float_eq!(just_intonation::<60, 440>(midi), hz, abs <= 1e-2);
+        }
+        // Just intonation in A, whose tonic is then the reference pitch.
+        for (midi, hz) in [(69.0, 440.0), (73.0, 550.0), (76.0, 660.0), (57.0, 220.0)] {
+            assert_float_eq!(just_intonation::<69, 440>(midi), hz, abs <= 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_just_7_limit() {
+        for (midi, hz) in [
+            (60.0, 261.626),
+            (62.0, 299.001),
+            (66.0, 366.276),
+            (67.0, 392.438),
+            (70.0, 457.845),
+        ] {
+            assert_float_eq!(just_intonation_7_limit::<60, 440>(midi), hz, abs <= 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_equal_temperament() {
+        for midi in 0..128 {
+            let midi = midi as f32;
+            assert_float_eq!(edo::<12, 69, 440>(midi), midi_hz(midi), rmax <= 1e-5);
+        }
+        for (midi, hz) in [
+            (69.0, 440.0),
+            (88.0, 880.0),
+            (50.0, 220.0),
+            (80.0, 657.254),
+            (70.0, 456.348),
+        ] {
+            assert_float_eq!(edo::<19, 69, 440>(midi), hz, abs <= 1e-2);
+        }
+        for (midi, hz) in [
+            (60.0, 200.0),
+            (73.0, 600.0),
+            (47.0, 66.667),
+            (66.0, 332.078),
+        ] {
+            assert_float_eq!(bohlen_pierce::<60, 200>(midi), hz, abs <= 1e-2);
+        }
+        let alpha = EqualTemperament::from_step_cents(78.0, 60, 261.626);
+        assert_float_eq!(
+            alpha.midi_to_hz(69.0),
+            261.626 * 2.0_f32.powf(702.0 / 1200.0),
+            abs <= 1e-2
+        );
+    }
+
+    #[test]
+    fn test_temperaments() {
+        let werckmeister = Temperament::werckmeister_iii(0, 440.0);
+        for (midi, hz) in [
+            (57.0, 220.0),
+            (60.0, 263.404),
+            (64.0, 330.0),
+            (72.0, 526.808),
+        ] {
+            assert_float_eq!(werckmeister.midi_to_hz(midi), hz, abs <= 1e-2);
+        }
+        let pythagorean = Temperament::pythagorean(2, 415.0);
+        for (midi, hz) in [
+            (69.0, 415.0),
+            (62.0, 276.667),
+            (66.0, 350.156),
+            (74.0, 553.333),
+        ] {
+            assert_float_eq!(pythagorean.midi_to_hz(midi), hz, abs <= 1e-2);
+        }
+        let meantone = Temperament::quarter_comma_meantone(0, 440.0);
+        let third = meantone.midi_to_hz(64.0) / meantone.midi_to_hz(60.0);
+        assert_float_eq!(third, 1.25, abs <= 1e-5);
+    }
 }
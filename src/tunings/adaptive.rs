@@ -0,0 +1,129 @@
+//! Adaptive just intonation, which retunes held notes to pure intervals above the root of the chord they form.
+//!
+//! No fixed tuning can play every chord in just intonation: a `RatioTuning` in C plays a pure C major
+//! chord but an impure D major chord. An `AdaptiveJust` tuning instead finds the root of the notes
+//! currently held, and tunes each of them by its ratio to that root. When the harmony changes, held notes
+//! either jump to their new frequencies or glide to them over a chosen duration.
+//!
+//! The lowest held note of the root's pitch class keeps the frequency given by the player's underlying
+//! tuning, so that repeated changes of harmony cannot cause the pitch to drift.
+
+use std::time::Duration;
+
+use num_rational::Ratio;
+
+use crate::tunings::Tuning;
+
+/// How strongly each interval above a candidate root suggests that it is the root of a chord.
+/// Fifths count most, then thirds, then sevenths and the tones of diminished and extended chords.
+const ROOT_EVIDENCE: [u8; 12] = [0, 0, 1, 2, 2, 0, 1, 3, 0, 1, 1, 1];
+
+/// Tunes held notes by their ratios to the root of the chord they form.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AdaptiveJust {
+    /// The ratio to the root of each of the twelve pitches in an octave, such as `FIVE_LIMIT`.
+    pub ratios: [Ratio<u16>; 12],
+    /// Time taken by held notes to glide to their new frequencies when the harmony changes.
+    /// If zero, they jump instead.
+    pub glide: Duration,
+}
+
+impl AdaptiveJust {
+    /// Creates an adaptive tuning by `ratios` whose held notes jump to their new frequencies.
+    pub fn new(ratios: [Ratio<u16>; 12]) -> Self {
+        Self {
+            ratios,
+            glide: Duration::ZERO,
+        }
+    }
+
+    /// Held notes will glide to their new frequencies over `glide` when the harmony changes.
+    pub fn with_glide(mut self, glide: Duration) -> Self {
+        self.glide = glide;
+        self
+    }
+
+    /// Returns the frequency in Hz of each of the `held` MIDI pitches, by its ratio to their `chord_root()`.
+    /// The lowest held note of the root's pitch class is tuned by `base`.
+    pub fn frequencies(&self, held: &[u8], base: &dyn Tuning) -> Vec<f32> {
+        let Some(root) = chord_root(held) else {
+            return vec![];
+        };
+        let root_hz = base.midi_to_hz(root as f32);
+        held.iter()
+            .map(|pitch| {
+                let semitones = *pitch as i32 - root as i32;
+                let ratio = self.ratios[semitones.rem_euclid(12) as usize];
+                root_hz * 2.0_f32.powi(semitones.div_euclid(12)) * *ratio.numer() as f32
+                    / *ratio.denom() as f32
+            })
+            .collect()
+    }
+}
+
+/// Returns the lowest of `pitches` whose pitch class is the root of the chord they form, or `None` if
+/// `pitches` is empty.
+///
+/// Each pitch class is scored by the intervals to the other pitch classes above it, with a perfect fifth
+/// counting most, followed by major and minor thirds. Ties go to the lower pitch, so that the bass note
+/// is the root of an ambiguous chord.
+pub fn chord_root(pitches: &[u8]) -> Option<u8> {
+    let mut by_height = pitches.to_vec();
+    by_height.sort_unstable();
+    by_height.dedup();
+    let mut best = None;
+    for candidate in by_height.iter() {
+        let score: u32 = by_height
+            .iter()
+            .map(|pitch| {
+                let interval = (*pitch as i32 - *candidate as i32).rem_euclid(12);
+                ROOT_EVIDENCE[interval as usize] as u32
+            })
+            .sum();
+        if best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((*candidate, score));
+        }
+    }
+    best.map(|(root, _)| root)
+}
+
+#[cfg(test)]
+mod tests {
+    use float_eq::assert_float_eq;
+    use fundsp::math::midi_hz;
+
+    use crate::tunings::{
+        FIVE_LIMIT,
+        adaptive::{AdaptiveJust, chord_root},
+    };
+
+    #[test]
+    fn test_chord_root() {
+        assert_eq!(chord_root(&[]), None);
+        assert_eq!(chord_root(&[64]), Some(64));
+        // C major, first inversion
+        assert_eq!(chord_root(&[64, 67, 72]), Some(72));
+        // G dominant seventh, first inversion
+        assert_eq!(chord_root(&[59, 62, 65, 67]), Some(67));
+        // D major over a low D and a high D
+        assert_eq!(chord_root(&[74, 66, 62, 69]), Some(62));
+        // A fifth alone
+        assert_eq!(chord_root(&[55, 62]), Some(55));
+    }
+
+    #[test]
+    fn test_adaptive_frequencies() {
+        let tuning = AdaptiveJust::new(FIVE_LIMIT);
+        let d = midi_hz(62.0);
+        for (held, expected) in [
+            (vec![62, 66, 69], vec![d, d * 5.0 / 4.0, d * 3.0 / 2.0]),
+            (vec![66, 74, 81], vec![d * 5.0 / 4.0, d * 2.0, d * 3.0]),
+            (vec![57, 62], vec![d * 3.0 / 4.0, d]),
+        ] {
+            let hz = tuning.frequencies(&held, &midi_hz::<f32>);
+            for (hz, expected) in hz.iter().zip(expected) {
+                assert_float_eq!(*hz, expected, rmax <= 1e-5);
+            }
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Support for the [MIDI Tuning Standard](https://midi.org/midi-tuning-updated-specification) (MTS),
+//! through which tuning software and hardware retune the keys of a synthesizer with SysEx messages.
+//!
+//! A player retunes its keys upon receiving a bulk tuning dump (`KeyBasedTuningDump`) or a single note
+//! tuning change (`TuningNoteChange`). Tuning programs and banks are not distinguished: every message
+//! retunes the keys currently in use. A real-time single note tuning change also retunes notes that
+//! are already sounding.
+
+use fundsp::math::midi_hz;
+use midi_msg::{
+    DeviceID, KeyBasedTuningDump, MidiMsg, SystemExclusiveMsg, Tuning as MtsTuning,
+    TuningNoteChange, UniversalNonRealTimeMsg, UniversalRealTimeMsg,
+};
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const NON_REAL_TIME: u8 = 0x7E;
+const REAL_TIME: u8 = 0x7F;
+const MIDI_TUNING: u8 = 0x08;
+const BULK_DUMP: u8 = 0x01;
+const SINGLE_NOTE: u8 = 0x02;
+const BULK_DUMP_WITH_BANK: u8 = 0x04;
+const SINGLE_NOTE_WITH_BANK: u8 = 0x07;
+const NAME_LEN: usize = 16;
+const NO_CHANGE: [u8; 3] = [0x7F, 0x7F, 0x7F];
+
+/// Returns the frequency in Hz represented by `tuning`.
+pub fn tuning_hz(tuning: &MtsTuning) -> f32 {
+    midi_hz(tuning.semitone as f32 + tuning.fraction as f32 / 16384.0)
+}
+
+/// Parses `bytes`, a complete SysEx message, if it is an MTS bulk tuning dump or single note tuning change.
+///
+/// [midi-msg](https://crates.io/crates/midi-msg) can encode these messages but not yet decode them,
+/// so input threads use this function to decode the messages it cannot.
+pub fn parse_tuning_sysex(bytes: &[u8]) -> Option<MidiMsg> {
+    let [
+        SYSEX_START,
+        kind,
+        device,
+        MIDI_TUNING,
+        sub_id,
+        body @ ..,
+        SYSEX_END,
+    ] = bytes
+    else {
+        return None;
+    };
+    let device = match device {
+        0x7F => DeviceID::AllCall,
+        id => DeviceID::Device(*id),
+    };
+    let msg = match (*kind, *sub_id) {
+        (NON_REAL_TIME, BULK_DUMP) => SystemExclusiveMsg::UniversalNonRealTime {
+            device,
+            msg: UniversalNonRealTimeMsg::KeyBasedTuningDump(parse_bulk_dump(None, body)?),
+        },
+        (NON_REAL_TIME, BULK_DUMP_WITH_BANK) => {
+            let (bank, body) = body.split_first()?;
+            SystemExclusiveMsg::UniversalNonRealTime {
+                device,
+                msg: UniversalNonRealTimeMsg::KeyBasedTuningDump(parse_bulk_dump(
+                    Some(*bank),
+                    body,
+                )?),
+            }
+        }
+        (REAL_TIME, SINGLE_NOTE) => SystemExclusiveMsg::UniversalRealTime {
+            device,
+            msg: UniversalRealTimeMsg::TuningNoteChange(parse_note_change(None, body)?),
+        },
+        (REAL_TIME, SINGLE_NOTE_WITH_BANK) => {
+            let (bank, body) = body.split_first()?;
+            SystemExclusiveMsg::UniversalRealTime {
+                device,
+                msg: UniversalRealTimeMsg::TuningNoteChange(parse_note_change(Some(*bank), body)?),
+            }
+        }
+        (NON_REAL_TIME, SINGLE_NOTE_WITH_BANK) => {
+            let (bank, body) = body.split_first()?;
+            SystemExclusiveMsg::UniversalNonRealTime {
+                device,
+                msg: UniversalNonRealTimeMsg::TuningNoteChange(parse_note_change(
+                    Some(*bank),
+                    body,
+                )?),
+            }
+        }
+        _ => return None,
+    };
+    Some(MidiMsg::SystemExclusive { msg })
+}
+
+/// If `msg` is an MTS message, returns each retuned key with its new frequency in Hz, along with
+/// whether the message is real-time and should thus retune sounding notes as well.
+pub(crate) fn retuned_keys(msg: &MidiMsg) -> Option<(Vec<(u8, f32)>, bool)> {
+    let MidiMsg::SystemExclusive { msg } = msg else {
+        return None;
+    };
+    match msg {
+        SystemExclusiveMsg::UniversalNonRealTime {
+            msg: UniversalNonRealTimeMsg::KeyBasedTuningDump(dump),
+            ..
+        } => Some((
+            dump.tunings
+                .iter()
+                .enumerate()
+                .filter_map(|(key, tuning)| tuning.map(|tuning| (key as u8, tuning_hz(&tuning))))
+                .collect(),
+            false,
+        )),
+        SystemExclusiveMsg::UniversalNonRealTime {
+            msg: UniversalNonRealTimeMsg::TuningNoteChange(change),
+            ..
+        } => Some((note_changes(change), false)),
+        SystemExclusiveMsg::UniversalRealTime {
+            msg: UniversalRealTimeMsg::TuningNoteChange(change),
+            ..
+        } => Some((note_changes(change), true)),
+        _ => None,
+    }
+}
+
+fn note_changes(change: &TuningNoteChange) -> Vec<(u8, f32)> {
+    change
+        .tunings
+        .iter()
+        .filter_map(|(key, tuning)| tuning.map(|tuning| (*key, tuning_hz(&tuning))))
+        .collect()
+}
+
+fn parse_bulk_dump(bank: Option<u8>, body: &[u8]) -> Option<KeyBasedTuningDump> {
+    // The checksum following the 128 tunings is ignored, as many senders compute it inconsistently.
+    let (&program, body) = body.split_first()?;
+    let name = body.get(..NAME_LEN)?.try_into().ok()?;
+    let tunings = body[NAME_LEN..]
+        .chunks_exact(3)
+        .take(128)
+        .map(parse_tuning)
+        .collect::<Vec<_>>();
+    if tunings.len() < 128 {
+        return None;
+    }
+    Some(KeyBasedTuningDump {
+        tuning_program_num: program,
+        tuning_bank_num: bank,
+        name,
+        tunings,
+    })
+}
+
+fn parse_note_change(bank: Option<u8>, body: &[u8]) -> Option<TuningNoteChange> {
+    let [program, count, changes @ ..] = body else {
+        return None;
+    };
+    let tunings = changes
+        .chunks_exact(4)
+        .take(*count as usize)
+        .map(|change| (change[0], parse_tuning(&change[1..])))
+        .collect::<Vec<_>>();
+    if tunings.len() < *count as usize {
+        return None;
+    }
+    Some(TuningNoteChange {
+        tuning_program_num: *program,
+        tuning_bank_num: bank,
+        tunings,
+    })
+}
+
+fn parse_tuning(bytes: &[u8]) -> Option<MtsTuning> {
+    if bytes == NO_CHANGE {
+        None
+    } else {
+        Some(MtsTuning {
+            semitone: bytes[0],
+            fraction: (bytes[1] as u16) << 7 | bytes[2] as u16,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use float_eq::assert_float_eq;
+    use midi_msg::{
+        DeviceID, KeyBasedTuningDump, MidiMsg, SystemExclusiveMsg, Tuning, TuningNoteChange,
+        UniversalNonRealTimeMsg, UniversalRealTimeMsg,
+    };
+
+    use crate::tunings::mts::{parse_tuning_sysex, retuned_keys};
+
+    #[test]
+    fn test_mts_round_trip() {
+        let quarter_sharp = Tuning {
+            semitone: 69,
+            fraction: 8192,
+        };
+        let change = MidiMsg::SystemExclusive {
+            msg: SystemExclusiveMsg::UniversalRealTime {
+                device: DeviceID::AllCall,
+                msg: UniversalRealTimeMsg::TuningNoteChange(TuningNoteChange {
+                    tuning_program_num: 0,
+                    tuning_bank_num: None,
+                    tunings: vec![(69, Some(quarter_sharp)), (70, None)],
+                }),
+            },
+        };
+        let parsed = parse_tuning_sysex(&change.to_midi()).unwrap();
+        assert_eq!(parsed, change);
+        let (keys, real_time) = retuned_keys(&parsed).unwrap();
+        assert!(real_time);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].0, 69);
+        assert_float_eq!(keys[0].1, 452.893, abs <= 1e-2);
+
+        let dump = MidiMsg::SystemExclusive {
+            msg: SystemExclusiveMsg::UniversalNonRealTime {
+                device: DeviceID::Device(3),
+                msg: UniversalNonRealTimeMsg::KeyBasedTuningDump(KeyBasedTuningDump {
+                    tuning_program_num: 1,
+                    tuning_bank_num: Some(2),
+                    name: *b"Quarter sharp   ",
+                    tunings: (0..128)
+                        .map(|key| {
+                            Some(Tuning {
+                                semitone: key,
+                                fraction: 8192,
+                            })
+                        })
+                        .collect(),
+                }),
+            },
+        };
+        let parsed = parse_tuning_sysex(&dump.to_midi()).unwrap();
+        assert_eq!(parsed, dump);
+        let (keys, real_time) = retuned_keys(&parsed).unwrap();
+        assert!(!real_time);
+        assert_eq!(keys.len(), 128);
+        assert_float_eq!(keys[69].1, 452.893, abs <= 1e-2);
+    }
+}
@@ -0,0 +1,366 @@
+//! Import of tunings from [Scala](https://www.huygens-fokker.org/scala/) files.
+//!
+//! A scale file (`.scl`) lists the pitches of a scale as ratios or cents above its first note. A keyboard mapping
+//! file (`.kbm`) determines which MIDI keys play which scale degrees, and the frequency of one reference key.
+//! Both formats are described [here](https://www.huygens-fokker.org/scala/scl_format.html) and
+//! [here](https://www.huygens-fokker.org/scala/help.htm#mappings).
+
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+
+use crate::{NUM_MIDI_VALUES, tunings::Tuning};
+
+/// The pitches of a scale loaded from a Scala `.scl` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    /// The description line of the file.
+    pub description: String,
+    /// The ratio to the first note of each degree after the first. The last ratio is the interval
+    /// at which the scale repeats, usually the octave `2.0`. If empty, every degree plays the first note.
+    pub ratios: Vec<f64>,
+}
+
+impl Scale {
+    /// Parses the contents of a `.scl` file.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut lines = data_lines(text);
+        let description = lines
+            .next()
+            .ok_or_else(|| anyhow!("Missing scale description"))?
+            .to_owned();
+        let count: usize = first_token(lines.next())?.parse()?;
+        let ratios = lines
+            .take(count)
+            .map(|line| parse_pitch(first_token(Some(line))?))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if ratios.len() < count {
+            bail!("Expected {count} pitches but found {}", ratios.len());
+        }
+        Ok(Self {
+            description,
+            ratios,
+        })
+    }
+
+    /// Loads the `.scl` file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Returns the ratio to the first note of `degree`, which may lie outside of the first repetition of the scale.
+    pub fn ratio(&self, degree: i32) -> f64 {
+        if self.ratios.is_empty() {
+            return 1.0;
+        }
+        let len = self.ratios.len() as i32;
+        let period = self.ratios[self.ratios.len() - 1];
+        let step = degree.rem_euclid(len);
+        let within = if step == 0 {
+            1.0
+        } else {
+            self.ratios[step as usize - 1]
+        };
+        period.powi(degree.div_euclid(len)) * within
+    }
+}
+
+/// Which MIDI keys play which scale degrees, loaded from a Scala `.kbm` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyboardMapping {
+    /// Lowest MIDI key to be played.
+    pub first_key: u8,
+    /// Highest MIDI key to be played.
+    pub last_key: u8,
+    /// MIDI key playing the first degree of the scale.
+    pub middle_key: u8,
+    /// MIDI key whose frequency is given by `reference_hz`.
+    pub reference_key: u8,
+    /// Frequency in Hz of `reference_key`.
+    pub reference_hz: f64,
+    /// Scale degree at which the mapping repeats. If `0`, the mapping repeats with the scale.
+    pub octave_degree: usize,
+    /// Scale degree of each key in one repetition of the mapping, starting at `middle_key`.
+    /// A `None` entry leaves its keys silent. If empty, consecutive keys play consecutive degrees.
+    pub mapping: Vec<Option<i32>>,
+}
+
+impl Default for KeyboardMapping {
+    /// Consecutive keys play consecutive degrees, with middle C playing the first degree at its
+    /// equal-tempered frequency.
+    fn default() -> Self {
+        Self {
+            first_key: 0,
+            last_key: (NUM_MIDI_VALUES - 1) as u8,
+            middle_key: 60,
+            reference_key: 60,
+            reference_hz: 261.6255653005986,
+            octave_degree: 0,
+            mapping: vec![],
+        }
+    }
+}
+
+impl KeyboardMapping {
+    /// Parses the contents of a `.kbm` file.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut lines = data_lines(text);
+        let size: usize = first_token(lines.next())?.parse()?;
+        let first_key = first_token(lines.next())?.parse()?;
+        let last_key = first_token(lines.next())?.parse()?;
+        let middle_key = first_token(lines.next())?.parse()?;
+        let reference_key = first_token(lines.next())?.parse()?;
+        let reference_hz = first_token(lines.next())?.parse()?;
+        let octave_degree = first_token(lines.next())?.parse()?;
+        let mapping = lines
+            .take(size)
+            .map(|line| match first_token(Some(line))? {
+                "x" => Ok(None),
+                degree => Ok(Some(degree.parse()?)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if mapping.len() < size {
+            bail!(
+                "Expected {size} mapping entries but found {}",
+                mapping.len()
+            );
+        }
+        Ok(Self {
+            first_key,
+            last_key,
+            middle_key,
+            reference_key,
+            reference_hz,
+            octave_degree,
+            mapping,
+        })
+    }
+
+    /// Loads the `.kbm` file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Returns the ratio of the frequency of `key` to that of `middle_key`, or `None` if `key` is not played.
+    fn ratio(&self, scale: &Scale, key: u8) -> Option<f64> {
+        if key < self.first_key || key > self.last_key {
+            return None;
+        }
+        let offset = key as i32 - self.middle_key as i32;
+        if self.mapping.is_empty() {
+            return Some(scale.ratio(offset));
+        }
+        let size = self.mapping.len() as i32;
+        let degree = self.mapping[offset.rem_euclid(size) as usize]?;
+        let period = if self.octave_degree == 0 {
+            scale.ratio(scale.ratios.len() as i32)
+        } else {
+            scale.ratio(self.octave_degree as i32)
+        };
+        Some(period.powi(offset.div_euclid(size)) * scale.ratio(degree))
+    }
+}
+
+/// A `Tuning` that plays a `Scale` according to a `KeyboardMapping`.
+///
+/// Keys that the mapping leaves silent do not play.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScalaTuning {
+    frequencies: Vec<Option<f32>>,
+}
+
+impl ScalaTuning {
+    /// Tunes every MIDI key by playing `scale` according to `mapping`. Returns an error if `mapping`
+    /// leaves its reference key silent.
+    pub fn new(scale: &Scale, mapping: &KeyboardMapping) -> anyhow::Result<Self> {
+        let reference = mapping
+            .ratio(scale, mapping.reference_key)
+            .ok_or_else(|| anyhow!("Reference key {} is unmapped", mapping.reference_key))?;
+        let middle_hz = mapping.reference_hz / reference;
+        let frequencies = (0..NUM_MIDI_VALUES)
+            .map(|key| {
+                mapping
+                    .ratio(scale, key as u8)
+                    .map(|ratio| (middle_hz * ratio) as f32)
+            })
+            .collect();
+        Ok(Self { frequencies })
+    }
+
+    /// Loads the `.scl` file at `scl_path`, mapping it onto the keyboard with `KeyboardMapping::default()`.
+    pub fn load<P: AsRef<Path>>(scl_path: P) -> anyhow::Result<Self> {
+        Self::new(&Scale::load(scl_path)?, &KeyboardMapping::default())
+    }
+
+    /// Loads the `.scl` file at `scl_path`, mapping it onto the keyboard with the `.kbm` file at `kbm_path`.
+    pub fn load_mapped<P: AsRef<Path>, Q: AsRef<Path>>(
+        scl_path: P,
+        kbm_path: Q,
+    ) -> anyhow::Result<Self> {
+        Self::new(&Scale::load(scl_path)?, &KeyboardMapping::load(kbm_path)?)
+    }
+
+    /// Returns the frequency in Hz of MIDI `key`, or `None` if it is not played.
+    pub fn frequency(&self, key: u8) -> Option<f32> {
+        self.frequencies.get(key as usize).copied().flatten()
+    }
+}
+
+impl Tuning for ScalaTuning {
+    fn midi_to_hz(&self, midi_pitch: f32) -> f32 {
+        self.frequency(midi_pitch as u8).unwrap_or(0.0)
+    }
+
+    fn plays(&self, key: u8) -> bool {
+        self.frequency(key).is_some()
+    }
+}
+
+/// Returns the lines of a Scala file that are not comments.
+fn data_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('!'))
+}
+
+/// Returns the first whitespace-delimited token of `line`, as any text that follows is ignored.
+fn first_token(line: Option<&str>) -> anyhow::Result<&str> {
+    line.and_then(|line| line.split_whitespace().next())
+        .ok_or_else(|| anyhow!("Unexpected end of Scala file"))
+}
+
+/// Parses a pitch given in cents, if it contains a period, or otherwise as a ratio such as `3/2` or `2`.
+fn parse_pitch(pitch: &str) -> anyhow::Result<f64> {
+    let ratio = if pitch.contains('.') {
+        2.0_f64.powf(pitch.parse::<f64>()? / 1200.0)
+    } else if let Some((numer, denom)) = pitch.split_once('/') {
+        numer.parse::<u64>()? as f64 / denom.parse::<u64>()? as f64
+    } else {
+        pitch.parse::<u64>()? as f64
+    };
+    if !ratio.is_finite() || ratio <= 0.0 {
+        bail!("Invalid pitch '{pitch}'");
+    }
+    Ok(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use fundsp::math::midi_hz;
+
+    use crate::tunings::{
+        KeyTable, Tuning,
+        scala::{KeyboardMapping, ScalaTuning, Scale},
+    };
+    use float_eq::assert_float_eq;
+
+    const EQUAL_12: &str = "! 12-tet.scl
+!
+12 tone equal temperament
+ 12
+!
+ 100.0 cents
+ 200.
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 2/1
+";
+
+    const JUST_MAJOR: &str = "! major.scl
+Just major scale
+7
+9/8
+5/4
+4/3
+3/2
+5/3
+15/8
+2
+";
+
+    const WHITE_KEYS: &str = "! white.kbm
+12
+0
+127
+60
+69
+440.0
+7
+! C to B, leaving the black keys silent
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+    #[test]
+    fn test_scala_equal_temperament() {
+        let tuning = ScalaTuning::new(
+            &Scale::parse(EQUAL_12).unwrap(),
+            &KeyboardMapping::default(),
+        )
+        .unwrap();
+        for key in 0..=127 {
+            let hz = tuning.frequency(key).unwrap();
+            assert_float_eq!(hz, midi_hz(key as f32), rmax <= 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_scala_keyboard_mapping() {
+        let tuning = ScalaTuning::new(
+            &Scale::parse(JUST_MAJOR).unwrap(),
+            &KeyboardMapping::parse(WHITE_KEYS).unwrap(),
+        )
+        .unwrap();
+        for (key, hz) in [
+            (60, Some(264.0)),
+            (61, None),
+            (62, Some(297.0)),
+            (64, Some(330.0)),
+            (67, Some(396.0)),
+            (69, Some(440.0)),
+            (71, Some(495.0)),
+            (72, Some(528.0)),
+            (48, Some(132.0)),
+        ] {
+            match hz {
+                Some(hz) => assert_float_eq!(tuning.frequency(key).unwrap(), hz, abs <= 1e-3),
+                None => assert_eq!(tuning.frequency(key), None),
+            }
+            assert_eq!(tuning.plays(key), hz.is_some());
+        }
+        let keys = KeyTable::new(&tuning);
+        assert!(keys.plays(60));
+        assert!(!keys.plays(61));
+    }
+
+    #[test]
+    fn test_scala_indented_comments_and_empty_scale() {
+        let scale = Scale::parse(
+            "  ! unison.scl\n  !\nUnison only\n 0\n\t! nothing follows the first note\n",
+        )
+        .unwrap();
+        assert_eq!(scale.description, "Unison only");
+        assert!(scale.ratios.is_empty());
+        let tuning = ScalaTuning::new(&scale, &KeyboardMapping::default()).unwrap();
+        for key in [0, 60, 127] {
+            assert_float_eq!(tuning.frequency(key).unwrap(), 261.62556, rmax <= 1e-6);
+        }
+    }
+}
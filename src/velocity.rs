@@ -0,0 +1,87 @@
+//! Velocity curves, which determine how hard a key must be struck to play a note at a given volume.
+//!
+//! Keyboards differ widely in how the force of a keystroke translates to MIDI velocity. A `VelocityCurve`
+//! compensates, so that the same sound feels alike whichever keyboard plays it.
+
+use std::sync::Arc;
+
+use crate::{MAX_MIDI_VALUE, NUM_MIDI_VALUES};
+
+/// Converts the velocity of a `Note On` message into a volume from `0.0` to `1.0`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum VelocityCurve {
+    /// Volume proportional to velocity.
+    #[default]
+    Linear,
+    /// Volume rising slowly at low velocities and quickly at high ones, for keyboards that feel too loud.
+    /// Larger curvatures bend the curve more.
+    Exponential(f32),
+    /// Volume rising quickly at low velocities and slowly at high ones, for keyboards that feel too soft.
+    /// Larger curvatures bend the curve more.
+    Logarithmic(f32),
+    /// Volume changing slowly at both extremes and quickly in between. Steepness `1.0` is linear, and
+    /// larger values widen the extremes.
+    SCurve(f32),
+    /// The same volume, as given by this velocity, for every note.
+    Fixed(u8),
+    /// The velocity to play in place of each velocity received.
+    Table(Arc<[u8; NUM_MIDI_VALUES]>),
+}
+
+impl VelocityCurve {
+    /// Returns the volume, from `0.0` to `1.0`, of a note played at `velocity`. A curvature or steepness
+    /// that is not positive yields the linear volume.
+    pub fn volume(&self, velocity: u8) -> f32 {
+        let linear = |velocity: u8| velocity.min(MAX_MIDI_VALUE) as f32 / MAX_MIDI_VALUE as f32;
+        let v = linear(velocity);
+        match self {
+            Self::Linear => v,
+            Self::Exponential(curvature) if *curvature > 0.0 => {
+                ((curvature * v).exp() - 1.0) / (curvature.exp() - 1.0)
+            }
+            Self::Logarithmic(curvature) if *curvature > 0.0 => {
+                (curvature * v).ln_1p() / curvature.ln_1p()
+            }
+            Self::SCurve(steepness) if *steepness > 0.0 => {
+                let rising = v.powf(*steepness);
+                rising / (rising + (1.0 - v).powf(*steepness))
+            }
+            Self::Fixed(velocity) => linear(*velocity),
+            Self::Table(table) => linear(table[velocity.min(MAX_MIDI_VALUE) as usize]),
+            _ => v,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use float_eq::assert_float_eq;
+
+    use crate::velocity::VelocityCurve;
+
+    #[test]
+    fn test_velocity_curves() {
+        for curve in [
+            VelocityCurve::Linear,
+            VelocityCurve::Exponential(3.0),
+            VelocityCurve::Logarithmic(10.0),
+            VelocityCurve::SCurve(2.0),
+        ] {
+            assert_float_eq!(curve.volume(0), 0.0, abs <= 1e-6);
+            assert_float_eq!(curve.volume(127), 1.0, abs <= 1e-6);
+            for velocity in 1..=127 {
+                assert!(curve.volume(velocity) > curve.volume(velocity - 1));
+            }
+        }
+        assert!(VelocityCurve::Exponential(3.0).volume(64) < VelocityCurve::Linear.volume(64));
+        assert!(VelocityCurve::Logarithmic(10.0).volume(64) > VelocityCurve::Linear.volume(64));
+        assert!(VelocityCurve::SCurve(2.0).volume(32) < VelocityCurve::Linear.volume(32));
+        assert!(VelocityCurve::SCurve(2.0).volume(96) > VelocityCurve::Linear.volume(96));
+        assert_eq!(VelocityCurve::Fixed(127).volume(1), 1.0);
+        let mut table = [0; 128];
+        table[100] = 127;
+        assert_eq!(VelocityCurve::Table(Arc::new(table)).volume(100), 1.0);
+    }
+}
@@ -0,0 +1,139 @@
+//! Keyboard zones, which split the keyboard among sounds or layer sounds atop one another.
+//!
+//! Each `Zone` plays one program from the synthesizer's program table, responding only to the notes within
+//! its range of keys and velocities, and optionally only to one MIDI channel. Zones whose ranges do not
+//! overlap split the keyboard; zones whose ranges overlap play their sounds together.
+//!
+//! ```
+//! use midi_fundsp::zones::Zone;
+//!
+//! // A bass below middle C on the left, with strings above it on the right layered with a quiet pad
+//! // that only sounds when keys are struck softly.
+//! let zones = vec![
+//!     Zone::new(1).with_keys(0..=59).with_pan(-0.5).with_transpose(-12),
+//!     Zone::new(2).with_keys(60..=127).with_pan(0.5),
+//!     Zone::new(3).with_keys(60..=127).with_velocities(1..=63),
+//! ];
+//! ```
+
+use std::ops::RangeInclusive;
+
+use midi_msg::{Channel, ChannelVoiceMsg, MidiMsg};
+
+use crate::{MAX_MIDI_VALUE, io::Speaker, velocity::VelocityCurve};
+
+/// A range of the keyboard played by one sound.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+    /// Keys, before transposition, to which the zone responds.
+    pub keys: RangeInclusive<u8>,
+    /// `Note On` velocities to which the zone responds.
+    pub velocities: RangeInclusive<u8>,
+    /// The only channel to which the zone responds, or `None` for every channel.
+    pub channel: Option<Channel>,
+    /// Index in the program table of the zone's initial sound. A `Program Change` received by the zone
+    /// replaces it.
+    pub program: u8,
+    /// Messages sent to `Speaker::Left` or `Speaker::Right` reach only the zones for that speaker or for
+    /// `Speaker::Both`. The transposition of that speaker also applies to the zone.
+    pub speaker: Speaker,
+    /// Position of the zone's sound between the left speaker, at `-1.0`, and the right, at `1.0`.
+    /// At `0.0`, it plays at full volume through both.
+    pub pan: f32,
+    /// Semitones by which the zone transposes its notes.
+    pub transpose: i8,
+    /// Converts the velocities of the zone's notes into volumes. If `None`, the synthesizer's curve is used.
+    pub velocity_curve: Option<VelocityCurve>,
+}
+
+impl Zone {
+    /// Creates a zone that plays `program` through both speakers, in response to every key on every channel.
+    pub fn new(program: u8) -> Self {
+        Self {
+            keys: 0..=MAX_MIDI_VALUE,
+            velocities: 1..=MAX_MIDI_VALUE,
+            channel: None,
+            program,
+            speaker: Speaker::Both,
+            pan: 0.0,
+            transpose: 0,
+            velocity_curve: None,
+        }
+    }
+
+    /// Responds only to `keys`.
+    pub fn with_keys(mut self, keys: RangeInclusive<u8>) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Responds only to `Note On` messages with `velocities`.
+    pub fn with_velocities(mut self, velocities: RangeInclusive<u8>) -> Self {
+        self.velocities = velocities;
+        self
+    }
+
+    /// Responds only to messages on `channel`.
+    pub fn with_channel(mut self, channel: Channel) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Plays through `speaker` only, and responds only to messages sent to `speaker` or `Speaker::Both`.
+    pub fn with_speaker(mut self, speaker: Speaker) -> Self {
+        self.speaker = speaker;
+        self.pan = match speaker {
+            Speaker::Left => -1.0,
+            Speaker::Right => 1.0,
+            Speaker::Both => 0.0,
+        };
+        self
+    }
+
+    /// Plays at `pan`, from `-1.0` for the left speaker only to `1.0` for the right speaker only.
+    pub fn with_pan(mut self, pan: f32) -> Self {
+        self.pan = pan.clamp(-1.0, 1.0);
+        self
+    }
+
+    /// Transposes notes by `semitones`.
+    pub fn with_transpose(mut self, semitones: i8) -> Self {
+        self.transpose = semitones;
+        self
+    }
+
+    /// Converts the velocities of the zone's notes into volumes with `curve`, rather than with the
+    /// synthesizer's curve.
+    pub fn with_velocity_curve(mut self, curve: VelocityCurve) -> Self {
+        self.velocity_curve = Some(curve);
+        self
+    }
+
+    /// Returns the volume of the zone's sound in the left and right speakers.
+    pub(crate) fn gains(&self) -> (f32, f32) {
+        ((1.0 - self.pan).min(1.0), (1.0 + self.pan).min(1.0))
+    }
+
+    /// Returns `true` if the zone should respond to `msg`, sent to `speaker`.
+    ///
+    /// A `Note Off` is accepted regardless of key and velocity, as its zone cannot be known from the message
+    /// alone. Zones that are not playing its key ignore it.
+    pub(crate) fn accepts(&self, speaker: Speaker, msg: &MidiMsg) -> bool {
+        if speaker != Speaker::Both && self.speaker != Speaker::Both && speaker != self.speaker {
+            return false;
+        }
+        match msg {
+            MidiMsg::ChannelVoice { channel, msg } => {
+                self.channel.is_none_or(|c| c == *channel)
+                    && match msg {
+                        ChannelVoiceMsg::NoteOn { note, velocity } if *velocity > 0 => {
+                            self.keys.contains(note) && self.velocities.contains(velocity)
+                        }
+                        _ => true,
+                    }
+            }
+            MidiMsg::ChannelMode { channel, .. } => self.channel.is_none_or(|c| c == *channel),
+            _ => true,
+        }
+    }
+}